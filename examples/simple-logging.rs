@@ -1,6 +1,6 @@
 fn main() {
     use fenrir_rs::{Fenrir, NetworkingBackend, SerializationFormat};
-    use log::{debug, error, info, set_boxed_logger, set_max_level, trace, warn, LevelFilter};
+    use log::{debug, error, info, set_max_level, trace, warn, LevelFilter};
     use url::Url;
 
     let my_loki = Fenrir::builder()
@@ -11,8 +11,8 @@ fn main() {
         .tag("service", "simple-logging")
         .build();
 
-    // set the actual logger for the facade
-    set_boxed_logger(Box::new(my_loki)).unwrap();
+    // set the actual logger for the facade (keep the guard alive until the application exits)
+    let guard = my_loki.register_global().unwrap();
     set_max_level(LevelFilter::Trace);
 
     // use the regular log macros for actual logging in the app
@@ -21,4 +21,7 @@ fn main() {
     info!("This is a INFO message");
     warn!("This is a WARN message");
     error!("This is a ERROR message");
+
+    // send all buffered log messages to Loki before exiting
+    guard.shutdown(std::time::Duration::from_secs(5));
 }
@@ -0,0 +1,194 @@
+//! A module which contains the error type used throughout the crate.
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// The [`FenrirError`] describes everything which can go wrong while sending logging messages to
+/// a Loki endpoint.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum FenrirError {
+    /// The request could not be sent to the remote endpoint (e.g. the name could not be resolved or
+    /// the connection was refused)
+    Transport(Box<dyn Error + Send + Sync>),
+
+    /// The remote endpoint answered with an HTTP status code indicating an error
+    HttpStatus {
+        /// The HTTP status code returned by the remote endpoint
+        status: u16,
+        /// The body of the response (might be empty)
+        body: String,
+        /// The delay requested by the remote endpoint using the `Retry-After` header (if any)
+        retry_after: Option<Duration>,
+    },
+
+    /// The logging messages could not be serialized or compressed
+    Serialization(Box<dyn Error + Send + Sync>),
+
+    /// The remote endpoint did not answer in time
+    Timeout(Box<dyn Error + Send + Sync>),
+
+    /// The configuration is not valid (e.g. the endpoint URL cannot be used)
+    Configuration(String),
+
+    /// The credentials could not be obtained from the configured credential provider
+    Authentication(Box<dyn Error + Send + Sync>),
+
+    /// The log messages were dropped since the buffer was full
+    BufferFull,
+
+    /// The log messages could not be written to or read from the spool on disk
+    Spool(std::io::Error),
+
+    /// The log messages stored in the spool on disk were dropped to make room for newer ones
+    SpoolFull,
+
+    /// A structured logging value was not attached as a label since it violates the label policy
+    /// (the log message itself is still sent)
+    LabelRejected {
+        /// The key of the rejected value
+        key: String,
+        /// The reason why the value was rejected
+        reason: String,
+    },
+}
+
+impl FenrirError {
+    /// Get the HTTP status code returned by the remote endpoint or `None` if the error was not
+    /// caused by an HTTP status code indicating an error
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            FenrirError::HttpStatus { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl Display for FenrirError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FenrirError::Transport(error) => write!(f, "Could not reach Loki: {}", error),
+            FenrirError::HttpStatus { status, body, .. } if body.is_empty() => {
+                write!(f, "Loki answered with HTTP status {}", status)
+            }
+            FenrirError::HttpStatus { status, body, .. } => {
+                write!(f, "Loki answered with HTTP status {}: {}", status, body)
+            }
+            FenrirError::Serialization(error) => {
+                write!(f, "Could not serialize the logging messages: {}", error)
+            }
+            FenrirError::Timeout(error) => write!(f, "Loki did not answer in time: {}", error),
+            FenrirError::Configuration(message) => write!(f, "Invalid configuration: {}", message),
+            FenrirError::Authentication(error) => {
+                write!(f, "Could not obtain the credentials: {}", error)
+            }
+            FenrirError::BufferFull => write!(f, "The buffer for log messages is full"),
+            FenrirError::Spool(error) => write!(f, "Could not use the spool: {}", error),
+            FenrirError::SpoolFull => {
+                write!(f, "The spool for undelivered log messages is full")
+            }
+            FenrirError::LabelRejected { key, reason } => {
+                write!(f, "The label {} was rejected since {}", key, reason)
+            }
+        }
+    }
+}
+
+impl Error for FenrirError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FenrirError::Transport(error)
+            | FenrirError::Serialization(error)
+            | FenrirError::Timeout(error)
+            | FenrirError::Authentication(error) => Some(error.as_ref()),
+            FenrirError::Spool(error) => Some(error),
+            FenrirError::HttpStatus { .. }
+            | FenrirError::Configuration(_)
+            | FenrirError::BufferFull
+            | FenrirError::SpoolFull
+            | FenrirError::LabelRejected { .. } => None,
+        }
+    }
+}
+
+/// The [`DroppedBatchInfo`] describes the log entries which were dropped because of an error.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct DroppedBatchInfo {
+    /// The number of log entries which were dropped
+    pub entries: usize,
+    /// The size of the serialized log entries in bytes (`0` if they could not be serialized)
+    pub bytes: usize,
+}
+
+/// The function definition of the callback which is called for each error
+pub(crate) type ErrorCallback = dyn Fn(&FenrirError, DroppedBatchInfo) + Send + Sync;
+
+/// The [`ErrorHandler`] decides what happens if log entries could not be delivered to Loki.
+#[derive(Default)]
+pub(crate) struct ErrorHandler {
+    /// The callback supplied by the user (if any)
+    pub(crate) callback: Option<Box<ErrorCallback>>,
+    /// If set to `true`, the handler panics after calling the [`ErrorHandler::callback`]
+    pub(crate) panic_on_error: bool,
+}
+
+impl ErrorHandler {
+    /// Report that the log entries described by `info` were dropped because of `error`
+    pub(crate) fn report(&self, error: &FenrirError, info: DroppedBatchInfo) {
+        if let Some(callback) = &self.callback {
+            callback(error, info);
+        }
+        if self.panic_on_error {
+            panic!(
+                "Could not send {} log entries to Loki. The error was: {}",
+                info.entries, error
+            );
+        }
+    }
+
+    /// Report a problem which did not cause log entries to be dropped (which is never a reason to
+    /// panic)
+    pub(crate) fn warn(&self, error: &FenrirError) {
+        if let Some(callback) = &self.callback {
+            callback(
+                error,
+                DroppedBatchInfo {
+                    entries: 0,
+                    bytes: 0,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FenrirError;
+    use std::error::Error;
+
+    #[test]
+    fn the_source_of_an_error_is_preserved() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        let error = FenrirError::Transport(Box::new(io_error));
+
+        assert_eq!(error.to_string(), "Could not reach Loki: refused");
+        assert_eq!(error.source().unwrap().to_string(), "refused");
+        assert_eq!(error.status(), None);
+    }
+
+    #[test]
+    fn http_status_errors_include_the_response_body() {
+        let error = FenrirError::HttpStatus {
+            status: 429,
+            body: "too many requests".to_string(),
+            retry_after: None,
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "Loki answered with HTTP status 429: too many requests"
+        );
+        assert!(error.source().is_none());
+        assert_eq!(error.status(), Some(429));
+    }
+}
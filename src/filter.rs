@@ -0,0 +1,287 @@
+//! A module which contains the filtering of log messages based on their level, target and
+//! message.
+use log::{LevelFilter, Metadata};
+use std::str::FromStr;
+
+/// A [`Directive`] sets the maximum level of the log messages of a single target (and all of its
+/// sub-modules).
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Directive {
+    /// The target (e.g. `hyper` or `my_crate::db`) this directive applies to
+    pub(crate) target: String,
+    /// The maximum level of log messages of the target
+    pub(crate) level: LevelFilter,
+}
+
+/// A [`MessageFilter`] only lets log messages pass which match it.
+///
+/// If the `regex` feature is enabled, the filter is a regular expression. Otherwise, the log
+/// messages have to contain the filter as a substring.
+#[derive(Clone, Debug)]
+pub(crate) struct MessageFilter {
+    #[cfg(feature = "regex")]
+    inner: regex::Regex,
+    #[cfg(not(feature = "regex"))]
+    inner: String,
+}
+
+impl MessageFilter {
+    /// Create a new filter from the supplied pattern
+    pub(crate) fn new(pattern: &str) -> Result<MessageFilter, String> {
+        #[cfg(feature = "regex")]
+        let inner = regex::Regex::new(pattern).map_err(|error| error.to_string())?;
+        #[cfg(not(feature = "regex"))]
+        let inner = pattern.to_string();
+
+        Ok(MessageFilter { inner })
+    }
+
+    /// Check if the supplied log message matches the filter
+    pub(crate) fn is_match(&self, message: &str) -> bool {
+        #[cfg(feature = "regex")]
+        return self.inner.is_match(message);
+        #[cfg(not(feature = "regex"))]
+        return message.contains(self.inner.as_str());
+    }
+}
+
+/// The [`Filter`] decides which log messages should be sent to Loki.
+#[derive(Clone, Debug)]
+pub(crate) struct Filter {
+    /// The maximum level for all log messages which are not matched by any [`Directive`]
+    pub(crate) max_level: LevelFilter,
+    /// The target specific directives, sorted by the length of their target (longest first)
+    pub(crate) directives: Vec<Directive>,
+    /// The filter the log message itself has to match (if any)
+    pub(crate) message: Option<MessageFilter>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            max_level: LevelFilter::Trace,
+            directives: Vec::new(),
+            message: None,
+        }
+    }
+}
+
+impl Filter {
+    /// Set the maximum level of the log messages of `target` and all of its sub-modules
+    pub(crate) fn set_level_for(&mut self, target: &str, level: LevelFilter) {
+        self.directives
+            .retain(|directive| directive.target != target);
+        self.directives.push(Directive {
+            target: target.to_string(),
+            level,
+        });
+        // the most specific directive should be found first when looking up the level
+        self.directives
+            .sort_by_key(|directive| std::cmp::Reverse(directive.target.len()));
+    }
+
+    /// Get the maximum level of the log messages of `target`
+    pub(crate) fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .find(|directive| is_same_or_child_module(target, &directive.target))
+            .map_or(self.max_level, |directive| directive.level)
+    }
+
+    /// Check if a log message with the supplied `metadata` should be sent to Loki
+    pub(crate) fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    /// Check if the (formatted) log message should be sent to Loki
+    pub(crate) fn matches_message(&self, message: &str) -> bool {
+        match &self.message {
+            Some(filter) => filter.is_match(message),
+            None => true,
+        }
+    }
+
+    /// Parse directives in the format used by `env_logger` (e.g.
+    /// `info,my_crate::db=debug,hyper=off/some.*message`) and apply them to this filter.
+    ///
+    /// Each comma-separated directive is either a level (which sets the maximum level), a target
+    /// (which enables all levels for this target) or a `target=level` pair. The directives can be
+    /// followed by a slash and a filter for the log messages. If any directive is invalid, the
+    /// filter is left unchanged.
+    pub(crate) fn parse_directives(&mut self, spec: &str) -> Result<(), String> {
+        let mut filter = self.clone();
+        match filter.parse_directives_lossy(spec).into_iter().next() {
+            Some(error) => Err(error),
+            None => {
+                *self = filter;
+                Ok(())
+            }
+        }
+    }
+
+    /// Parse directives like [`Filter::parse_directives`], but skip the invalid ones (like
+    /// `env_logger` does) and return an error message for each of them.
+    pub(crate) fn parse_directives_lossy(&mut self, spec: &str) -> Vec<String> {
+        let (directives, message) = match spec.split_once('/') {
+            Some((directives, message)) => (directives, Some(message)),
+            None => (spec, None),
+        };
+
+        let mut errors = Vec::new();
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let Ok(level) = LevelFilter::from_str(level.trim()) else {
+                        errors.push(format!("invalid level in directive '{}'", directive));
+                        continue;
+                    };
+                    if target.trim().is_empty() {
+                        errors.push(format!("missing target in directive '{}'", directive));
+                        continue;
+                    }
+                    self.set_level_for(target.trim(), level);
+                }
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => self.max_level = level,
+                    Err(_) => self.set_level_for(directive, LevelFilter::Trace),
+                },
+            }
+        }
+
+        if let Some(message) = message {
+            match MessageFilter::new(message) {
+                Ok(filter) => self.message = Some(filter),
+                Err(error) => errors.push(error),
+            }
+        }
+        errors
+    }
+}
+
+/// Check if `target` is the module `module` itself or one of its sub-modules (so `hyper` matches
+/// `hyper::client`, but not `hyper_util`)
+pub(crate) fn is_same_or_child_module(target: &str, module: &str) -> bool {
+    target
+        .strip_prefix(module)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, MessageFilter};
+    use log::{Level, LevelFilter, Metadata};
+
+    fn enabled(filter: &Filter, level: Level, target: &str) -> bool {
+        filter.enabled(&Metadata::builder().level(level).target(target).build())
+    }
+
+    #[test]
+    fn the_maximum_level_applies_to_all_targets_without_directive() {
+        let filter = Filter {
+            max_level: LevelFilter::Info,
+            ..Filter::default()
+        };
+        assert!(enabled(&filter, Level::Error, "app"));
+        assert!(enabled(&filter, Level::Info, "app"));
+        assert!(!enabled(&filter, Level::Debug, "app"));
+    }
+
+    #[test]
+    fn the_most_specific_directive_is_used_for_a_target() {
+        let mut filter = Filter::default();
+        filter.set_level_for("hyper", LevelFilter::Warn);
+        filter.set_level_for("app", LevelFilter::Info);
+        filter.set_level_for("app::db", LevelFilter::Trace);
+
+        assert_eq!(filter.level_for("hyper"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("hyper::client::pool"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("hyper_util"), LevelFilter::Trace);
+        assert_eq!(filter.level_for("app::http"), LevelFilter::Info);
+        assert_eq!(filter.level_for("app::db::pool"), LevelFilter::Trace);
+        assert!(!enabled(&filter, Level::Info, "hyper::client"));
+        assert!(enabled(&filter, Level::Trace, "app::db"));
+    }
+
+    #[test]
+    fn setting_the_level_for_a_target_again_replaces_the_directive() {
+        let mut filter = Filter::default();
+        filter.set_level_for("hyper", LevelFilter::Warn);
+        filter.set_level_for("hyper", LevelFilter::Off);
+
+        assert_eq!(filter.directives.len(), 1);
+        assert_eq!(filter.level_for("hyper"), LevelFilter::Off);
+    }
+
+    #[test]
+    fn directives_in_the_env_logger_format_can_be_parsed() {
+        let mut filter = Filter::default();
+        filter
+            .parse_directives("info, my_crate::db=debug,hyper=off,verbose_crate")
+            .unwrap();
+
+        assert_eq!(filter.max_level, LevelFilter::Info);
+        assert_eq!(filter.level_for("app"), LevelFilter::Info);
+        assert_eq!(filter.level_for("my_crate::db::pool"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("hyper::client"), LevelFilter::Off);
+        assert_eq!(filter.level_for("verbose_crate"), LevelFilter::Trace);
+        assert!(filter.message.is_none());
+    }
+
+    #[test]
+    fn directives_with_a_message_filter_can_be_parsed() {
+        let mut filter = Filter::default();
+        filter.parse_directives("warn/connection").unwrap();
+
+        assert_eq!(filter.max_level, LevelFilter::Warn);
+        assert!(filter.matches_message("lost connection to the database"));
+        assert!(!filter.matches_message("user logged in"));
+    }
+
+    #[test]
+    fn invalid_directives_are_rejected() {
+        assert!(Filter::default().parse_directives("hyper=loud").is_err());
+        assert!(Filter::default().parse_directives("=info").is_err());
+
+        // the filter is not changed partially
+        let mut filter = Filter::default();
+        assert!(filter.parse_directives("warn,hyper=loud").is_err());
+        assert_eq!(filter.max_level, LevelFilter::Trace);
+    }
+
+    #[test]
+    fn invalid_directives_are_skipped_when_parsing_leniently() {
+        let mut filter = Filter::default();
+        let errors = filter.parse_directives_lossy("warn,hyper=loud,=info,app=debug");
+
+        assert_eq!(
+            errors,
+            vec![
+                "invalid level in directive 'hyper=loud'".to_string(),
+                "missing target in directive '=info'".to_string()
+            ]
+        );
+        assert_eq!(filter.max_level, LevelFilter::Warn);
+        assert_eq!(filter.level_for("hyper"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("app"), LevelFilter::Debug);
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn message_filters_are_regular_expressions_if_the_regex_feature_is_enabled() {
+        let filter = MessageFilter::new("^user [0-9]+ logged in$").unwrap();
+        assert!(filter.is_match("user 42 logged in"));
+        assert!(!filter.is_match("user admin logged in"));
+        assert!(MessageFilter::new("(unclosed").is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "regex"))]
+    fn message_filters_are_substrings_if_the_regex_feature_is_disabled() {
+        let filter = MessageFilter::new("logged in").unwrap();
+        assert!(filter.is_match("user 42 logged in"));
+        assert!(!filter.is_match("user 42 logged out"));
+    }
+}
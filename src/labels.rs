@@ -0,0 +1,335 @@
+//! A module which contains the policy used for limiting the labels which are attached to log
+//! messages using structured logging.
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// The keys of the structured logging values which may be attached as labels
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum LabelKeys {
+    /// All keys may be used as labels
+    All,
+    /// Only the listed keys may be used as labels
+    Allow(Vec<String>),
+    /// All keys except for the listed ones may be used as labels
+    Deny(Vec<String>),
+}
+
+impl LabelKeys {
+    /// Only allow the supplied keys to be used as labels
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::LabelKeys;
+    ///
+    /// let keys = LabelKeys::allow(&["service", "region"]);
+    /// assert_eq!(keys, LabelKeys::Allow(vec!["service".to_string(), "region".to_string()]));
+    /// ```
+    pub fn allow(keys: &[&str]) -> LabelKeys {
+        LabelKeys::Allow(keys.iter().map(|key| key.to_string()).collect())
+    }
+
+    /// Allow all keys except for the supplied ones to be used as labels
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::LabelKeys;
+    ///
+    /// let keys = LabelKeys::deny(&["user_id"]);
+    /// assert_eq!(keys, LabelKeys::Deny(vec!["user_id".to_string()]));
+    /// ```
+    pub fn deny(keys: &[&str]) -> LabelKeys {
+        LabelKeys::Deny(keys.iter().map(|key| key.to_string()).collect())
+    }
+}
+
+/// What happens to a structured logging value which violates the [`LabelPolicy`]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum RejectedLabelAction {
+    /// The value is appended to the log line (e.g. `message user_id=4711`)
+    MoveToLine,
+    /// The value is dropped
+    Drop,
+}
+
+/// The [`LabelPolicy`] limits the labels which are attached to log messages using structured
+/// logging, since each distinct label set creates a new stream in Loki.
+///
+/// The labels configured on the builder (like [`crate::FenrirBuilder::tag`]) are never rejected,
+/// but they count towards [`LabelPolicy::max_labels`]. The first violation of a key in each
+/// [`LabelPolicy::window`] is reported to the callback set with
+/// [`crate::FenrirBuilder::on_error`] (using [`crate::FenrirError::LabelRejected`]).
+///
+/// A policy is created using [`LabelPolicy::new`] (which does not limit the labels at all) and
+/// adjusted using its `with_*` methods.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use fenrir_rs::{LabelKeys, LabelPolicy, RejectedLabelAction};
+///
+/// let policy = LabelPolicy::new()
+///     .with_keys(LabelKeys::deny(&["user_id"]))
+///     .with_max_labels(10)
+///     .with_max_values_per_key(100)
+///     .with_window(Duration::from_secs(600))
+///     .with_rejected(RejectedLabelAction::Drop);
+/// ```
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[non_exhaustive]
+pub struct LabelPolicy {
+    /// The keys which may be used as labels (after they were turned into valid label names, e.g.
+    /// `http_method` for `http.method`)
+    pub keys: LabelKeys,
+    /// The maximum number of labels of a single stream
+    pub max_labels: Option<usize>,
+    /// The maximum number of distinct values of a single key within the
+    /// [`LabelPolicy::window`]
+    pub max_values_per_key: Option<usize>,
+    /// The time window in which the distinct values of each key are counted
+    pub window: Duration,
+    /// What happens to values which violate the policy
+    pub rejected: RejectedLabelAction,
+}
+
+impl Default for LabelPolicy {
+    fn default() -> Self {
+        LabelPolicy {
+            keys: LabelKeys::All,
+            max_labels: None,
+            max_values_per_key: None,
+            window: Duration::from_secs(3600),
+            rejected: RejectedLabelAction::MoveToLine,
+        }
+    }
+}
+
+impl LabelPolicy {
+    /// Create a policy which does not limit the labels at all (values of new keys are counted
+    /// within a window of one hour and moved into the log line if they are rejected)
+    pub fn new() -> LabelPolicy {
+        LabelPolicy::default()
+    }
+
+    /// Set the keys which may be used as labels
+    pub fn with_keys(mut self, keys: LabelKeys) -> LabelPolicy {
+        self.keys = keys;
+        self
+    }
+
+    /// Set the maximum number of labels of a single stream
+    pub fn with_max_labels(mut self, max_labels: usize) -> LabelPolicy {
+        self.max_labels = Some(max_labels);
+        self
+    }
+
+    /// Set the maximum number of distinct values of a single key within the window
+    ///
+    /// # Panics
+    /// This method will panic if `max_values_per_key` is 0.
+    pub fn with_max_values_per_key(mut self, max_values_per_key: usize) -> LabelPolicy {
+        if max_values_per_key == 0 {
+            panic!("The label policy must allow at least one value per key");
+        }
+        self.max_values_per_key = Some(max_values_per_key);
+        self
+    }
+
+    /// Set the time window in which the distinct values of each key are counted
+    ///
+    /// # Panics
+    /// This method will panic if the `window` is zero.
+    pub fn with_window(mut self, window: Duration) -> LabelPolicy {
+        if window.is_zero() {
+            panic!("The window of the label policy must be greater than 0");
+        }
+        self.window = window;
+        self
+    }
+
+    /// Set what happens to values which violate the policy
+    pub fn with_rejected(mut self, rejected: RejectedLabelAction) -> LabelPolicy {
+        self.rejected = rejected;
+        self
+    }
+}
+
+/// A structured logging value which was rejected as a label
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct RejectedLabel {
+    /// The key of the value
+    pub(crate) key: String,
+    /// The value itself
+    pub(crate) value: String,
+    /// The reason why the value was rejected (if it has to be reported)
+    pub(crate) warning: Option<&'static str>,
+}
+
+/// The values of each key which were seen in the current window
+struct LabelState {
+    /// The point in time the current window started at
+    window_start: Instant,
+    /// The distinct values of each key
+    values: HashMap<String, HashSet<String>>,
+    /// The keys whose violations were already reported in the current window
+    reported: HashSet<String>,
+}
+
+/// The [`LabelGuard`] applies a [`LabelPolicy`] to the labels of the log messages.
+pub(crate) struct LabelGuard {
+    /// The policy which is applied
+    pub(crate) policy: LabelPolicy,
+    /// The values of each key which were seen in the current window
+    state: Mutex<LabelState>,
+}
+
+impl LabelGuard {
+    /// Create a new guard for the supplied policy
+    pub(crate) fn new(policy: LabelPolicy) -> LabelGuard {
+        LabelGuard {
+            policy,
+            state: Mutex::new(LabelState {
+                window_start: Instant::now(),
+                values: HashMap::new(),
+                reported: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Add the `candidates` to the `labels` if they comply with the policy and return the ones
+    /// which were rejected.
+    pub(crate) fn apply(
+        &self,
+        labels: &mut BTreeMap<String, String>,
+        candidates: BTreeMap<String, String>,
+    ) -> Vec<RejectedLabel> {
+        let mut state = self.state.lock();
+        if state.window_start.elapsed() >= self.policy.window {
+            state.window_start = Instant::now();
+            state.values.clear();
+            state.reported.clear();
+        }
+
+        let mut rejected = Vec::new();
+        for (key, value) in candidates {
+            let reason = match &self.policy.keys {
+                LabelKeys::Allow(keys) if !keys.contains(&key) => Some("the key is not allowed"),
+                LabelKeys::Deny(keys) if keys.contains(&key) => Some("the key is denied"),
+                _ => None,
+            };
+            let reason = reason.or_else(|| match self.policy.max_labels {
+                Some(max_labels) if labels.len() >= max_labels && !labels.contains_key(&key) => {
+                    Some("the stream has too many labels")
+                }
+                _ => None,
+            });
+            let reason = reason.or_else(|| {
+                let max_values = self.policy.max_values_per_key?;
+                let values = state.values.entry(key.clone()).or_default();
+                if values.contains(&value) || values.len() < max_values {
+                    values.insert(value.clone());
+                    return None;
+                }
+                Some("the key has too many distinct values")
+            });
+
+            match reason {
+                Some(reason) => {
+                    let warning = state.reported.insert(key.clone()).then_some(reason);
+                    rejected.push(RejectedLabel {
+                        key,
+                        value,
+                        warning,
+                    });
+                }
+                None => {
+                    labels.insert(key, value);
+                }
+            }
+        }
+        rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::labels::{LabelGuard, LabelKeys, LabelPolicy};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    /// Create a map of labels from the supplied pairs
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn keys_which_are_not_allowed_are_rejected() {
+        let guard = LabelGuard::new(LabelPolicy::new().with_keys(LabelKeys::allow(&["service"])));
+        let mut accepted = BTreeMap::new();
+        let rejected = guard.apply(
+            &mut accepted,
+            labels(&[("service", "app"), ("user_id", "4711")]),
+        );
+        assert_eq!(accepted, labels(&[("service", "app")]));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].key, "user_id");
+        assert_eq!(rejected[0].warning, Some("the key is not allowed"));
+
+        // each violation is only reported once per window
+        let rejected = guard.apply(&mut BTreeMap::new(), labels(&[("user_id", "42")]));
+        assert_eq!(rejected[0].warning, None);
+
+        let guard = LabelGuard::new(LabelPolicy::new().with_keys(LabelKeys::deny(&["user_id"])));
+        let mut accepted = BTreeMap::new();
+        guard.apply(
+            &mut accepted,
+            labels(&[("service", "app"), ("user_id", "4711")]),
+        );
+        assert_eq!(accepted, labels(&[("service", "app")]));
+    }
+
+    #[test]
+    fn the_number_of_labels_per_stream_is_limited() {
+        let guard = LabelGuard::new(LabelPolicy::new().with_max_labels(2));
+        let mut accepted = labels(&[("level", "INFO")]);
+        let rejected = guard.apply(
+            &mut accepted,
+            labels(&[("a", "1"), ("b", "2"), ("level", "WARN")]),
+        );
+        assert_eq!(accepted, labels(&[("a", "1"), ("level", "WARN")]));
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].key, "b");
+    }
+
+    #[test]
+    fn the_distinct_values_of_a_key_are_limited_within_the_window() {
+        let guard = LabelGuard::new(
+            LabelPolicy::new()
+                .with_max_values_per_key(2)
+                .with_window(Duration::from_millis(50)),
+        );
+        let apply = |value: &str| {
+            guard
+                .apply(&mut BTreeMap::new(), labels(&[("user_id", value)]))
+                .is_empty()
+        };
+        assert!(apply("1"));
+        assert!(apply("2"));
+        assert!(apply("1"));
+        assert!(!apply("3"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(apply("3"));
+    }
+
+    #[test]
+    #[should_panic(expected = "The window of the label policy must be greater than 0")]
+    fn a_label_policy_with_an_empty_window_panics() {
+        let _policy = LabelPolicy::new().with_window(Duration::ZERO);
+    }
+}
@@ -1,28 +1,94 @@
 #![doc = include_str!("../README.md")]
 
+mod credentials;
+mod error;
+mod filter;
+#[cfg(feature = "structured_logging")]
+mod labels;
+mod logfmt;
 pub mod noop;
+#[cfg(feature = "protobuf")]
+mod protobuf;
 #[cfg(feature = "reqwest-async")]
 pub mod reqwest;
+mod retry;
+mod spool;
 #[cfg(feature = "ureq")]
 pub mod ureq;
+mod worker;
 
-use cfg_if::cfg_if;
+pub use credentials::{
+    AsyncCredentialProvider, Credential, CredentialFuture, CredentialProvider, FileTokenProvider,
+};
+use credentials::{CredentialCache, Provider};
+pub use error::{DroppedBatchInfo, FenrirError};
+use error::{ErrorCallback, ErrorHandler};
+use filter::{is_same_or_child_module, Filter};
+#[cfg(feature = "structured_logging")]
+use labels::LabelGuard;
+#[cfg(feature = "structured_logging")]
+pub use labels::{LabelKeys, LabelPolicy, RejectedLabelAction};
 #[cfg(feature = "structured_logging")]
 use log::kv::{Source, Visitor};
-use log::{Log, Metadata, Record};
-use parking_lot::RwLock;
-use serde::Serialize;
-use std::collections::HashMap;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use parking_lot::{Condvar, Mutex};
+use retry::is_retryable;
+pub use retry::RetryPolicy;
+use serde::ser::SerializeTuple;
+use serde::{Serialize, Serializer};
+use spool::{DeliveryReceipt, Spool};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use url::Url;
+use worker::{FlushWorker, WorkerSignal};
 
 /// The [`AuthenticationMethod`] enum is used to specify the authentication method to use when
 /// sending the log messages to the remote endpoint.
-#[derive(Clone, Eq, PartialEq, Debug)]
+///
+/// The [`Debug`] output of this enum does not contain any secrets, so it can be logged safely.
+#[derive(Clone, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum AuthenticationMethod {
     /// Do not use any authentication when sending the log messages to the remote endpoint
     None,
     /// Use the HTTP Basic Auth when sending the log messages to the remote endpoint
     Basic,
+    /// Use the supplied bearer token (`Authorization: Bearer <token>`) when sending the log
+    /// messages to the remote endpoint
+    Bearer(String),
+}
+
+impl AuthenticationMethod {
+    /// Get the value of the `Authorization` header for this method and the supplied (encoded)
+    /// `credentials` or `None` if no header should be sent
+    #[cfg_attr(
+        not(any(feature = "ureq", feature = "reqwest-async")),
+        allow(dead_code) // only used by the network backends
+    )]
+    pub(crate) fn authorization(&self, credentials: &str) -> Option<String> {
+        match self {
+            AuthenticationMethod::None => None,
+            AuthenticationMethod::Basic => Some(format!("Basic {}", credentials)),
+            AuthenticationMethod::Bearer(token) => Some(format!("Bearer {}", token)),
+        }
+    }
+}
+
+impl Debug for AuthenticationMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthenticationMethod::None => f.write_str("None"),
+            AuthenticationMethod::Basic => f.write_str("Basic"),
+            AuthenticationMethod::Bearer(_) => f
+                .debug_tuple("Bearer")
+                .field(&format_args!("<redacted>"))
+                .finish(),
+        }
+    }
 }
 
 /// The [`NetworkingBackend`] defines all possible networking backends which can be used within
@@ -55,7 +121,7 @@ impl NetworkingBackend {
 
 /// The [`SerializationFormat`] is used to configure the format to which the logging messages should
 /// be serialized to before sending them to the Loki endpoint.
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum SerializationFormat {
     /// Do not serialize the data at all
     None,
@@ -63,25 +129,305 @@ pub enum SerializationFormat {
     /// Use JSON as the serialization format
     #[cfg(feature = "json")]
     Json,
+
+    /// Use the snappy-compressed protobuf encoding of Loki's `logproto.PushRequest` as the
+    /// serialization format
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl SerializationFormat {
+    /// Get the value of the `Content-Type` header for data serialized in this format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            SerializationFormat::None => "application/octet-stream",
+            #[cfg(feature = "json")]
+            SerializationFormat::Json => "application/json; charset=utf-8",
+            #[cfg(feature = "protobuf")]
+            SerializationFormat::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    /// Get the value of the `Content-Encoding` header for data serialized in this format or `None`
+    /// if the serialized data is not encoded any further
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            SerializationFormat::None => None,
+            #[cfg(feature = "json")]
+            SerializationFormat::Json => None,
+            #[cfg(feature = "protobuf")]
+            SerializationFormat::Protobuf => Some("snappy"),
+        }
+    }
+}
+
+/// The [`OverflowPolicy`] decides what happens to new log messages if the buffer of [`Fenrir`] is
+/// full (see [`FenrirBuilder::max_buffered_entries`] and [`FenrirBuilder::max_buffered_bytes`]).
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    /// Drop the new log message
+    DropNewest,
+
+    /// Drop the oldest buffered log messages until the new one fits into the buffer
+    DropOldest,
+
+    /// Ask the worker thread to flush the buffer and wait up to the supplied time for the buffer
+    /// to have enough space for the new log message (which is dropped afterward). The worker
+    /// thread is started automatically if this policy is selected.
+    Block(Duration),
+}
+
+/// The [`Compression`] is used to configure how the serialized logging messages are compressed
+/// before sending them to the Loki endpoint.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Compression {
+    /// Do not compress the serialized logging messages
+    None,
+
+    /// Compress the serialized logging messages using gzip
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Compression {
+    /// Compress the `payload` using this compression method
+    pub(crate) fn compress(&self, payload: Vec<u8>) -> Result<Vec<u8>, FenrirError> {
+        match self {
+            Compression::None => Ok(payload),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use flate2::write::GzEncoder;
+                use std::io::Write;
+
+                let mut encoder = GzEncoder::new(
+                    Vec::with_capacity(payload.len() / 4),
+                    flate2::Compression::default(),
+                );
+                encoder
+                    .write_all(&payload)
+                    .map_err(|error| FenrirError::Serialization(Box::new(error)))?;
+                encoder
+                    .finish()
+                    .map_err(|error| FenrirError::Serialization(Box::new(error)))
+            }
+        }
+    }
+
+    /// Get the value of the `Content-Encoding` header for data compressed with this compression
+    /// method or `None` if the data is not compressed
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Some("gzip"),
+        }
+    }
+}
+
+/// The [`LineFormat`] is used to configure how a single logging message is formatted as the log
+/// line of its entry in Loki.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub enum LineFormat {
+    /// Use the plain message as the log line (the default if the `json-log-fmt` feature is not
+    /// enabled)
+    #[cfg_attr(not(feature = "json-log-fmt"), default)]
+    Message,
+
+    /// Use a JSON object containing the message and its meta information (like the file and the
+    /// line number) as the log line (requires the `json` feature and is the default if the
+    /// `json-log-fmt` feature is enabled)
+    #[cfg(feature = "json")]
+    #[cfg_attr(feature = "json-log-fmt", default)]
+    Json,
+
+    /// Use the logfmt format for the log line (e.g. `level=info target=app msg="Hello World"`), so
+    /// it can be parsed by the `| logfmt` expression of LogQL. The values attached using structured
+    /// logging are added as additional fields, except for the ones dropped by the label policy.
+    /// Characters which cannot be part of a key are replaced with `_` and fields whose keys are
+    /// already used (e.g. by the meta information like `level` or `msg`) are prefixed with
+    /// underscores until they are unique (e.g. `_level`).
+    Logfmt,
 }
 
 /// The function definition which is used to serialize the logging messages for Loki
-pub(crate) type SerializationFn = fn(&Streams) -> Result<Vec<u8>, String>;
+pub(crate) type SerializationFn = fn(&Streams) -> Result<Vec<u8>, FenrirError>;
+
+/// The time [`Fenrir`] waits for outstanding log messages to be delivered when it is dropped
+///
+/// If the instance is dropped within a single-threaded tokio runtime, it does not wait at all,
+/// since the outstanding requests could not make any progress while the thread is blocked.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum length of a label name accepted by Loki (using its default limits)
+const MAX_LABEL_NAME_LENGTH: usize = 1024;
+
+/// The maximum length of a label value accepted by Loki (using its default limits)
+const MAX_LABEL_VALUE_LENGTH: usize = 2048;
+
+/// The approximate number of bytes a log entry adds to a serialized batch in addition to its log
+/// line (the timestamp and the JSON syntax around them)
+const ENTRY_OVERHEAD: usize = 32;
+
+/// The approximate number of bytes a label adds to a serialized batch in addition to its key and
+/// value
+const LABEL_OVERHEAD: usize = 6;
+
+/// The approximate number of bytes a stream adds to a serialized batch in addition to its labels
+/// and log entries
+const STREAM_OVERHEAD: usize = 24;
+
+/// A [`Batch`] of serialized log streams which should be sent to Loki by a [`FenrirBackend`].
+///
+/// Cloning a batch does not copy its payload.
+#[derive(Clone, Debug)]
+pub struct Batch {
+    /// The serialized log streams (shared with the copy kept for the spool while the batch is sent)
+    pub(crate) payload: Arc<Vec<u8>>,
+    /// The value of the `Content-Type` header to use for sending the [`Batch::payload`]
+    pub(crate) content_type: &'static str,
+    /// The value of the `Content-Encoding` header to use for sending the [`Batch::payload`] (if any)
+    pub(crate) content_encoding: Option<&'static str>,
+    /// The number of log entries contained in the serialized log streams
+    pub(crate) entries: usize,
+    /// The tenant the log entries belong to (if any)
+    pub(crate) tenant: Option<String>,
+    /// The log streams before they were serialized, so the batch can be split if Loki rejects it
+    /// as too large (only known for batches created by [`Fenrir`] itself)
+    pub(crate) streams: Option<Arc<Vec<Stream>>>,
+    /// The receipt used for reporting the delivery of a batch replayed from the spool
+    pub(crate) receipt: Option<DeliveryReceipt>,
+}
+
+impl Batch {
+    /// Create a new [`Batch`] of `entries` log entries which were serialized into `payload`.
+    ///
+    /// Batches are usually created by [`Fenrir`] itself, but this can be used for testing a custom
+    /// [`FenrirBackend`].
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Batch;
+    ///
+    /// let batch = Batch::new(b"{\"streams\":[]}".to_vec(), "application/json", 0)
+    ///     .with_content_encoding("gzip")
+    ///     .with_tenant("team-a");
+    /// assert_eq!(batch.content_encoding(), Some("gzip"));
+    /// assert_eq!(batch.tenant(), Some("team-a"));
+    /// ```
+    pub fn new(payload: Vec<u8>, content_type: &'static str, entries: usize) -> Batch {
+        Batch {
+            payload: Arc::new(payload),
+            content_type,
+            content_encoding: None,
+            entries,
+            tenant: None,
+            streams: None,
+            receipt: None,
+        }
+    }
+
+    /// Set the value of the `Content-Encoding` header which has to be sent with the request
+    pub fn with_content_encoding(mut self, content_encoding: &'static str) -> Batch {
+        self.content_encoding = Some(content_encoding);
+        self
+    }
+
+    /// Set the tenant the log entries belong to
+    pub fn with_tenant(mut self, tenant: &str) -> Batch {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// Get the serialized log streams which have to be used as the body of the request
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Take the serialized log streams which have to be used as the body of the request (they are
+    /// only copied if the batch is still kept for the spool while it is sent)
+    pub fn into_payload(self) -> Vec<u8> {
+        Arc::try_unwrap(self.payload).unwrap_or_else(|payload| payload.to_vec())
+    }
+
+    /// Get the value of the `Content-Type` header which has to be sent with the request
+    pub fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    /// Get the value of the `Content-Encoding` header which has to be sent with the request or
+    /// `None` if the header must not be sent
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        self.content_encoding
+    }
+
+    /// Get the number of log entries contained in the batch
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Get the tenant the log entries belong to or `None` if no tenant was configured. The tenant
+    /// has to be sent using the `X-Scope-OrgID` header.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+}
 
 /// This trait is used to specify the interfaces which are required for the communication
 /// with the remote endpoint.
-pub(crate) trait FenrirBackend {
-    /// Sends a `Streams` object to the configured remote backend
-    fn send(&self, serialized_stream: Vec<u8>) -> Result<(), String>;
+///
+/// It can be implemented for using a custom transport (see [`FenrirBuilder::custom_backend`]).
+///
+/// # Contract
+/// - [`FenrirBackend::send`] is called with a serialized [`Batch`] every time the buffered log
+///   messages are flushed. This happens either on the thread which created the log message that
+///   exceeded the flush threshold or on the background worker of [`Fenrir`], so the method should
+///   not block longer than necessary.
+/// - The batch has to be sent as the body of a `POST` request to the push API of Loki (usually
+///   `/loki/api/v1/push`) using the `Content-Type` and `Content-Encoding` of the batch. If the
+///   batch belongs to a tenant, it has to be sent using the `X-Scope-OrgID` header.
+/// - Returning an error reports the batch as undelivered (see [`FenrirBuilder::on_error`]). Batches
+///   are not sent again by [`Fenrir`], so retrying is up to the backend (see
+///   [`RetryPolicy::next_delay`]).
+/// - Backends which send the batches in the background cannot report errors using the return value
+///   and should implement [`FenrirBackend::shutdown`] for waiting for outstanding requests.
+/// - Log messages created by the backend (or the libraries it uses) are sent to Loki again, which
+///   creates an infinite loop. Their targets have to be returned by
+///   [`FenrirBackend::excluded_targets`].
+pub trait FenrirBackend: Send + Sync + 'static {
+    /// Sends a batch of serialized `Streams` to the configured remote backend
+    fn send(&self, batch: Batch) -> Result<(), FenrirError>;
+
+    /// Wait up to `timeout` for all batches which are still being sent in the background and
+    /// return the number of log entries which could not be delivered by the background tasks.
+    ///
+    /// Backends which send the batches synchronously do not have to implement this method.
+    fn shutdown(&self, _timeout: Duration) -> usize {
+        0
+    }
 
     /// Query the `TypeId` of the implementation of this trait
-    fn internal_type(&self) -> std::any::TypeId;
+    fn internal_type(&self) -> std::any::TypeId {
+        std::any::TypeId::of::<Self>()
+    }
 
     /// Get the configured `AuthenticationMethod` for the backend
-    fn authentication_method(&self) -> AuthenticationMethod;
+    fn authentication_method(&self) -> AuthenticationMethod {
+        AuthenticationMethod::None
+    }
 
     /// Get the configured credentials or `None` if no credentials are configured
-    fn credentials(&self) -> Option<String>;
+    fn credentials(&self) -> Option<String> {
+        None
+    }
+
+    /// Get the targets (crates or modules) whose log messages must not be sent using this backend.
+    ///
+    /// This should include the networking library used by the backend as well as all of its
+    /// dependencies which are logging, since sending their log messages would create new log
+    /// messages and therefore an infinite loop.
+    fn excluded_targets(&self) -> &'static [&'static str] {
+        &[]
+    }
 }
 
 /// The [`Fenrir`] struct implements the communication interface with a [Loki](https://grafana.com/oss/loki/)
@@ -89,14 +435,39 @@ pub(crate) trait FenrirBackend {
 ///
 /// To create a new instance of the [`Fenrir`] struct use the [`FenrirBuilder`] struct.
 pub struct Fenrir {
-    backend: Box<dyn FenrirBackend + Send + Sync>,
+    shipper: Arc<LogShipper>,
     additional_tags: HashMap<String, String>,
-    serializer: SerializationFn,
+    filter: Filter,
+    /// The targets whose log messages are never sent to Loki
+    excluded_targets: Vec<String>,
     include_level: bool,
     include_framework: bool,
-    log_stream: RwLock<Vec<Stream>>,
     flush_threshold: usize,
     max_message_size: Option<usize>,
+    /// The format of the log lines sent to Loki
+    line_format: LineFormat,
+    /// The tenant the log messages belong to if no other tenant was selected for them
+    tenant_id: Option<String>,
+    /// The key of the structured logging value which selects the tenant of a log message
+    #[cfg(feature = "structured_logging")]
+    tenant_key: Option<String>,
+    /// The keys of the structured logging values which are attached as structured metadata
+    #[cfg(feature = "structured_logging")]
+    metadata_keys: MetadataKeys,
+    /// The guard which limits the labels attached using structured logging (if configured)
+    #[cfg(feature = "structured_logging")]
+    label_guard: Option<LabelGuard>,
+    /// The keys of the structured logging values which were already reported since another key
+    /// has the same label name
+    #[cfg(feature = "structured_logging")]
+    reported_collisions: Mutex<std::collections::HashSet<String>>,
+    /// The interval in which the buffered log entries are flushed (if configured)
+    flush_interval: Option<Duration>,
+    /// The worker which flushes the buffered log entries in the background (if configured).
+    /// It is stopped as soon as the [`Fenrir`] instance is shut down.
+    flush_worker: Mutex<Option<FlushWorker>>,
+    /// The worker which replays the batches stored in the spool (if configured)
+    replay_worker: Mutex<Option<FlushWorker>>,
 }
 
 impl Fenrir {
@@ -112,23 +483,200 @@ impl Fenrir {
     pub fn builder() -> FenrirBuilder {
         FenrirBuilder {
             endpoint: Url::parse("http://localhost:3100").unwrap(),
+            push_path: "loki/api/v1/push".to_string(),
             authentication: AuthenticationMethod::None,
             network_backend: NetworkingBackend::None,
             serialization_format: SerializationFormat::None,
+            compression: Compression::None,
             additional_tags: HashMap::new(),
             credentials: "".to_string(),
+            filter: Filter::default(),
+            custom_backend: None,
+            excluded_targets: Vec::new(),
+            allowed_targets: Vec::new(),
             include_level: false,
             include_framework: false,
             runtime: None,
             flush_threshold: 100,
             max_message_size: None,
+            line_format: LineFormat::default(),
+            max_batch_bytes: None,
+            flush_interval: None,
+            background_dispatch: false,
+            buffer_limits: BufferLimits::default(),
+            spool: None,
+            request_timeout: Duration::from_secs(10),
+            connection_pool_size: 3,
+            idle_connection_timeout: Duration::from_secs(15),
+            retry_policy: RetryPolicy::default(),
+            headers: Vec::new(),
+            credential_provider: None,
+            tenant_id: None,
+            #[cfg(feature = "structured_logging")]
+            tenant_key: None,
+            #[cfg(feature = "structured_logging")]
+            metadata_keys: MetadataKeys::Selected(Vec::new()),
+            #[cfg(feature = "structured_logging")]
+            label_policy: None,
+            error_callback: None,
+            panic_on_error: false,
+            configuration_warnings: Vec::new(),
+        }
+    }
+
+    /// Flush all buffered log messages and wait up to `timeout` for log messages which are still
+    /// being sent in the background (e.g. by the `Reqwest` backend).
+    ///
+    /// The background worker for periodic flushing is stopped, but the instance can still be
+    /// used for logging afterward. The method returns the number of log entries which could not be
+    /// delivered to Loki since this instance was created.
+    ///
+    /// # Note
+    /// If the `Reqwest` backend is used, the outstanding requests have to be processed by another
+    /// thread of the runtime while this method is waiting. Calling it from within a
+    /// single-threaded runtime will therefore always run into the timeout, which is why dropping
+    /// an instance within such a runtime does not wait at all.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let fenrir = Fenrir::builder().build();
+    /// let undelivered = fenrir.shutdown(Duration::from_secs(5));
+    /// assert_eq!(undelivered, 0);
+    /// ```
+    pub fn shutdown(&self, timeout: Duration) -> usize {
+        // stop the background workers first, so they cannot race with the final flush
+        drop(self.flush_worker.lock().take());
+        drop(self.replay_worker.lock().take());
+
+        self.shipper.flush();
+        self.shipper.undelivered.load(Ordering::Relaxed) + self.shipper.backend.shutdown(timeout)
+    }
+
+    /// Get the number of log entries which were dropped since this instance was created, because
+    /// the buffer or the spool was full (see [`FenrirBuilder::overflow_policy`] and
+    /// [`FenrirBuilder::spool_dir`]) or because spooled log entries could not be replayed.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let fenrir = Fenrir::builder().build();
+    /// assert_eq!(fenrir.dropped_entries(), 0);
+    /// ```
+    pub fn dropped_entries(&self) -> usize {
+        let spooled = match &self.shipper.spool {
+            Some(spool) => spool.dropped.load(Ordering::Relaxed),
+            None => 0,
+        };
+        self.shipper.dropped.load(Ordering::Relaxed) + spooled
+    }
+
+    /// Register this instance as the global logger of the `log` crate.
+    ///
+    /// The returned [`FenrirGuard`] has to be kept alive until the application exits. As soon as
+    /// it is dropped (or [`FenrirGuard::shutdown`] is called), all buffered log messages are sent to
+    /// Loki. The global maximum log level still has to be set with [`log::set_max_level`].
+    ///
+    /// # Errors
+    /// An error is returned if a global logger was already registered.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    /// use log::LevelFilter;
+    ///
+    /// let guard = Fenrir::builder().build().register_global().unwrap();
+    /// log::set_max_level(LevelFilter::Info);
+    ///
+    /// log::info!("Hello Loki");
+    /// drop(guard);
+    /// ```
+    pub fn register_global(self) -> Result<FenrirGuard, SetLoggerError> {
+        let fenrir = Arc::new(self);
+        log::set_logger(Box::leak(Box::new(GlobalFenrir(fenrir.clone()))))?;
+        Ok(FenrirGuard {
+            fenrir,
+            is_shut_down: false,
+        })
+    }
+}
+
+impl Drop for Fenrir {
+    fn drop(&mut self) {
+        self.shutdown(drop_timeout());
+    }
+}
+
+/// Get the time to wait for outstanding log messages when an instance is dropped.
+///
+/// Within a single-threaded tokio runtime, the requests sent in the background cannot make any
+/// progress while the dropping thread is blocked, so waiting would only delay the drop.
+fn drop_timeout() -> Duration {
+    #[cfg(feature = "async-tokio")]
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::CurrentThread {
+            return Duration::ZERO;
+        }
+    }
+    DEFAULT_SHUTDOWN_TIMEOUT
+}
+
+/// The [`FenrirGuard`] is returned when registering a [`Fenrir`] instance as the global logger
+/// using [`Fenrir::register_global`].
+///
+/// Dropping the guard flushes all buffered log messages and waits up to
+/// [`DEFAULT_SHUTDOWN_TIMEOUT`] for them to be delivered (except within a single-threaded tokio
+/// runtime).
+pub struct FenrirGuard {
+    /// The instance which was registered as the global logger
+    fenrir: Arc<Fenrir>,
+    /// Set to `true` if the instance was already shut down explicitly
+    is_shut_down: bool,
+}
+
+impl FenrirGuard {
+    /// Flush all buffered log messages and wait up to `timeout` for them to be delivered.
+    ///
+    /// See [`Fenrir::shutdown`] for details. The method returns the number of log entries which
+    /// could not be delivered to Loki.
+    pub fn shutdown(mut self, timeout: Duration) -> usize {
+        self.is_shut_down = true;
+        self.fenrir.shutdown(timeout)
+    }
+}
+
+impl Drop for FenrirGuard {
+    fn drop(&mut self) {
+        if !self.is_shut_down {
+            self.fenrir.shutdown(drop_timeout());
         }
     }
 }
 
+/// The logger which is registered globally by [`Fenrir::register_global`]. It just forwards all
+/// calls to the shared [`Fenrir`] instance.
+struct GlobalFenrir(Arc<Fenrir>);
+
+impl Log for GlobalFenrir {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.log(record)
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
 impl Log for Fenrir {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true // TODO: use the metadata object to decide if we should be enabled or not
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
@@ -136,17 +684,19 @@ impl Log for Fenrir {
 
         // we do want to ignore logs which are created by the used networking library since this
         // would create an infinite loop
-        // TODO: this check should move into the backend implementation
-        let module = record.module_path().unwrap_or("");
-        if module.starts_with("ureq")
-            || module.starts_with("reqwest")
-            || !self.enabled(record.metadata())
-        {
+        if self.is_excluded(record) || !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // the message filter can only be applied after formatting the message
+        #[cfg_attr(not(feature = "structured_logging"), allow(unused_mut))]
+        let mut message = record.args().to_string();
+        if !self.filter.matches_message(&message) {
             return;
         }
 
         // a map with all labels which should be attached to the log entries
-        let mut labels = HashMap::new();
+        let mut labels = BTreeMap::new();
 
         // the default labels supplied with all entries
         if self.include_framework {
@@ -159,36 +709,96 @@ impl Log for Fenrir {
         // add the additional tags to the labels (this might overwrite existing labels)
         labels.extend(self.additional_tags.clone());
 
+        // the tenant can be overwritten for a single log message using structured logging
+        #[cfg_attr(not(feature = "structured_logging"), allow(unused_mut))]
+        let mut tenant = self.tenant_id.clone();
+
+        // values with a high cardinality are attached as structured metadata instead of labels
+        #[cfg_attr(not(feature = "structured_logging"), allow(unused_mut))]
+        let mut metadata = BTreeMap::new();
+
+        // the structured logging values which are added as fields to logfmt lines
+        #[cfg_attr(not(feature = "structured_logging"), allow(unused_mut))]
+        let mut fields = Vec::new();
+
         // if structured logging is enabled, add the labels which were attached at the single entries
         #[cfg(feature = "structured_logging")]
         {
             let kv = record.key_values();
             let mut visitor = LokiVisitor::new(kv.count());
-            let values = visitor.read_kv(kv).unwrap();
+            // the values are collected in an arbitrary order, but keys with the same label name
+            // have to be resolved the same way for each log message
+            let mut values = visitor.read_kv(kv).unwrap().iter().collect::<Vec<_>>();
+            values.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
 
-            labels.extend(
-                values
-                    .iter()
-                    .map(|(key, value)| (key.to_string(), value.to_string())),
-            );
-        }
+            let mut candidates = BTreeMap::new();
+            let mut names = std::collections::HashSet::new();
+            for (key, value) in values {
+                if self.tenant_key.as_deref() == Some(key.as_str()) {
+                    tenant = Some(value.to_string());
+                    continue;
+                }
+                // Loki rejects the whole batch if a single name or value is invalid
+                let name = sanitize_label_name(key.as_str());
+                if !names.insert(name.clone()) {
+                    self.report_collision(key.as_str());
+                    continue;
+                }
+                if self.line_format == LineFormat::Logfmt {
+                    fields.push((key.to_string(), value.to_string()));
+                }
+                let value = truncate_label_value(value.to_string());
+                if self.metadata_keys.contains(&name) {
+                    metadata.insert(name, value);
+                    continue;
+                }
+                candidates.insert(name, value);
+            }
 
-        let serialized_event = {
-            cfg_if! {
-                if #[cfg(feature = "json-log-fmt")] {
-                    serde_json::to_string(&SerializedEvent {
-                        file: record.file(),
-                        line: record.line(),
-                        module: record.module_path(),
-                        level: record.level().as_str(),
-                        target: record.target(),
-                        message: record.args().to_string(),
-                    })
-                    .expect("JSON serialization failed (should not happen)")
-                } else {
-                    record.args().to_string()
+            match &self.label_guard {
+                Some(label_guard) => {
+                    let mut dropped = Vec::new();
+                    for rejected in label_guard.apply(&mut labels, candidates) {
+                        // logfmt lines already contain all values as fields
+                        if label_guard.policy.rejected == RejectedLabelAction::MoveToLine
+                            && self.line_format != LineFormat::Logfmt
+                        {
+                            message.push_str(&format!(" {}={}", rejected.key, rejected.value));
+                        }
+                        if let Some(reason) = rejected.warning {
+                            self.shipper
+                                .error_handler
+                                .warn(&FenrirError::LabelRejected {
+                                    key: rejected.key.clone(),
+                                    reason: reason.to_string(),
+                                });
+                        }
+                        if label_guard.policy.rejected == RejectedLabelAction::Drop {
+                            dropped.push(rejected.key);
+                        }
+                    }
+                    // the rejected keys are label names, so the fields are matched on them too
+                    if !dropped.is_empty() {
+                        fields.retain(|(key, _)| !dropped.contains(&sanitize_label_name(key)));
+                    }
                 }
+                None => labels.extend(candidates),
             }
+        }
+
+        let serialized_event = match self.line_format {
+            LineFormat::Message => message,
+            #[cfg(feature = "json")]
+            LineFormat::Json => serde_json::to_string(&SerializedEvent {
+                file: record.file(),
+                line: record.line(),
+                module: record.module_path(),
+                level: record.level().as_str(),
+                target: record.target(),
+                message,
+            })
+            .expect("JSON serialization failed (should not happen)"),
+            LineFormat::Logfmt => logfmt::encode_line(record, &message, &fields),
         };
 
         if let Some(max_message_size) = self.max_message_size {
@@ -197,94 +807,626 @@ impl Log for Fenrir {
             }
         }
 
-        // create the logging stream we want to send to loki
-        let stream_object = Stream {
-            stream: labels,
-            values: vec![vec![
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos()
-                    .to_string(),
-                serialized_event,
-            ]],
-        };
-        // push the stream object to the log stream
-        let log_stream_size = {
-            let mut log_stream = self.log_stream.write();
-            log_stream.push(stream_object);
-            log_stream.len()
+        // the actual log entry consisting of the timestamp and the message itself
+        let entry = LogEntry {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            line: serialized_event,
+            metadata,
         };
 
+        // push the entry to the stream with the same tenant and label set
+        let log_stream_size = self.shipper.push(tenant, labels, entry);
+
+        // the logging thread has to flush entries which are due if the worker panicked
+        let is_overdue = self.flush_interval.is_some_and(|interval| {
+            self.shipper.worker_signal.has_panicked()
+                && self.shipper.flush_due_in(interval).is_zero()
+        });
+
         // check if we need to flush the logs
-        if log_stream_size >= self.flush_threshold {
-            self.flush();
+        if log_stream_size >= self.flush_threshold || is_overdue {
+            self.shipper.request_flush();
         }
     }
 
     fn flush(&self) {
-        // fetch and serialize the log streams
-        let res = {
-            // this route can save several allocations since we do not need to clone the streams,
-            // and we reuse the allocated memory
-            let mut streams = self.log_stream.write();
-            let res = (self.serializer)(&Streams { streams: &streams });
-            streams.clear();
-            res
+        self.shipper.flush();
+    }
+}
+
+impl Fenrir {
+    /// Check if the log message was created by one of the excluded targets (or their sub-modules)
+    fn is_excluded(&self, record: &Record) -> bool {
+        let module = record.module_path().unwrap_or("");
+        self.excluded_targets.iter().any(|excluded| {
+            is_same_or_child_module(module, excluded)
+                || is_same_or_child_module(record.target(), excluded)
+        })
+    }
+
+    /// Report that the structured logging value with the supplied `key` was not attached since
+    /// another key has the same label name (only once for each key)
+    #[cfg(feature = "structured_logging")]
+    fn report_collision(&self, key: &str) {
+        let is_new = self.reported_collisions.lock().insert(key.to_string());
+        if is_new {
+            self.shipper
+                .error_handler
+                .warn(&FenrirError::LabelRejected {
+                    key: key.to_string(),
+                    reason: "another key has the same label name".to_string(),
+                });
+        }
+    }
+}
+
+/// The [`LogShipper`] owns the buffered log entries as well as everything which is required to
+/// send them to Loki.
+///
+/// It is shared between the [`Fenrir`] instance and its (optional) background [`FlushWorker`].
+pub(crate) struct LogShipper {
+    /// The backend which is used to send the serialized log streams to Loki
+    pub(crate) backend: Box<dyn FenrirBackend>,
+    /// The encoder which turns the log streams into batches
+    pub(crate) encoder: Encoder,
+    /// The buffer of log entries which were not sent to Loki yet
+    pub(crate) log_stream: Mutex<LogBuffer>,
+    /// The limits of the [`LogShipper::log_stream`]
+    pub(crate) limits: BufferLimits,
+    /// The maximum (approximate) size of a single batch sent to Loki in bytes
+    pub(crate) max_batch_bytes: Option<usize>,
+    /// Notified every time the [`LogShipper::log_stream`] was emptied
+    pub(crate) space_available: Condvar,
+    /// The number of log entries which could not be delivered to Loki
+    pub(crate) undelivered: AtomicUsize,
+    /// The number of log entries which were dropped because the buffer was full
+    pub(crate) dropped: AtomicUsize,
+    /// If set to `true`, the buffer is flushed by the background worker instead of the logging
+    /// thread
+    pub(crate) background_dispatch: bool,
+    /// The signal used to ask the background worker thread to flush the buffer
+    pub(crate) worker_signal: Arc<WorkerSignal>,
+    /// The spool which keeps batches which could not be delivered on disk (if configured)
+    pub(crate) spool: Option<Arc<Spool>>,
+    /// The handler which is informed about log entries which could not be delivered
+    pub(crate) error_handler: Arc<ErrorHandler>,
+}
+
+impl LogShipper {
+    /// Add a log entry to the buffer (applying the [`OverflowPolicy`] if the buffer is full) and
+    /// return the number of buffered log entries afterward.
+    pub(crate) fn push(
+        &self,
+        tenant: Option<String>,
+        labels: BTreeMap<String, String>,
+        entry: LogEntry,
+    ) -> usize {
+        let size = entry.line.len();
+        let mut buffer = self.log_stream.lock();
+
+        // send the buffered log entries before the next batch would exceed its maximum size
+        if let Some(max_batch_bytes) = self.max_batch_bytes {
+            if !buffer.is_empty() && buffer.size + entry.approximate_size() > max_batch_bytes {
+                drop(buffer);
+                self.request_flush();
+                buffer = self.log_stream.lock();
+            }
+        }
+
+        let mut dropped = DroppedBatchInfo {
+            entries: 0,
+            bytes: 0,
         };
-        match res {
-            Ok(serialized_stream) => {
-                if let Err(e) = self.backend.send(serialized_stream) {
-                    #[cfg(debug_assertions)]
-                    panic!("Could not send logs to Loki. The error was: {}", e);
+
+        // log entries which could never fit into the buffer must not evict any other entries
+        if !self.limits.fits(&buffer, size) && self.limits.fits_empty_buffer(size) {
+            match self.limits.policy {
+                OverflowPolicy::DropNewest => {}
+                OverflowPolicy::DropOldest => {
+                    while !self.limits.fits(&buffer, size) {
+                        match buffer.pop_oldest() {
+                            Some(bytes) => {
+                                dropped.entries += 1;
+                                dropped.bytes += bytes;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+                OverflowPolicy::Block(timeout) => {
+                    // the buffer is flushed by the worker thread, so sending it cannot delay the
+                    // logging thread beyond the timeout (only after a shutdown, there is no
+                    // worker anymore and the buffer has to be flushed right here)
+                    drop(buffer);
+                    if !self.worker_signal.request_flush() {
+                        self.flush();
+                    }
+
+                    // other threads might have filled up the buffer again in the meantime
+                    let deadline = Instant::now().checked_add(timeout);
+                    buffer = self.log_stream.lock();
+                    while !self.limits.fits(&buffer, size) && !buffer.is_empty() {
+                        let timed_out = match deadline {
+                            Some(deadline) => self
+                                .space_available
+                                .wait_until(&mut buffer, deadline)
+                                .timed_out(),
+                            None => {
+                                self.space_available.wait(&mut buffer);
+                                false
+                            }
+                        };
+                        if timed_out {
+                            break;
+                        }
+                    }
                 }
             }
-            Err(message) => {
-                #[cfg(debug_assertions)]
-                panic!("Could not serialize logs. The error was: {}", message);
+        }
+
+        let buffered = match self.limits.fits(&buffer, size) {
+            true => buffer.push(tenant, labels, entry),
+            false => {
+                dropped.entries += 1;
+                dropped.bytes += size;
+                buffer.entries
             }
+        };
+        drop(buffer);
+
+        // the error handler must not be called while holding the lock on the buffer
+        if dropped.entries > 0 {
+            self.dropped.fetch_add(dropped.entries, Ordering::Relaxed);
+            self.error_handler.report(&FenrirError::BufferFull, dropped);
         }
+        buffered
     }
-}
 
-/// The [`FenrirBuilder`] struct is used to create a new instance of [`Fenrir`] using the builder pattern.
-///
-/// This should make it easier and more intuitive (at least I hope) to use the crate without referring
-/// to the documentation all the time.
-pub struct FenrirBuilder {
-    /// The loki `endpoint` which is used to send log information to
-    endpoint: Url,
-    /// The `authentication` method to use when sending the log messages to the remote endpoint
-    authentication: AuthenticationMethod,
-    /// The `network_backend` which should be used for the network requests
-    network_backend: NetworkingBackend,
-    /// The `serialization_format´ used for the logging messages
-    serialization_format: SerializationFormat,
-    /// A map of additional tags which should be attached to all log messages
-    additional_tags: HashMap<String, String>,
-    /// The `credentials` to use to authenticate against the remote `endpoint`
-    credentials: String,
-    /// If set to `true`, the logging level is included as a tag
-    include_level: bool,
-    /// If set to `true,` the logging framework (`fenrir-rs`) is included as a tag
-    include_framework: bool,
-    /// A runtime handle to the tokio runtime, if it is used
-    #[cfg(feature = "async-tokio")]
-    runtime: Option<tokio::runtime::Handle>,
-    #[cfg(not(feature = "async-tokio"))]
-    runtime: Option<()>,
-    /// Number of log messages after which to flush all outstanding messages to Loki.
-    /// Defaults to 100.
-    /// Must be greater than 0.
-    flush_threshold: usize,
-    /// Skip a log message if its serialized representation is larger than this value in bytes.
-    /// Defaults to None, which means no limit.
-    /// If set, must be greater than 0.
-    max_message_size: Option<usize>,
-}
+    /// Flush the buffer on the background worker thread (if it is responsible for flushing) or
+    /// on the current thread otherwise.
+    pub(crate) fn request_flush(&self) {
+        if !(self.background_dispatch && self.worker_signal.request_flush()) {
+            self.flush();
+        }
+    }
 
-impl FenrirBuilder {
-    /// Set the endpoint which should be used for sending the log messages to.
+    /// Serialize all buffered log entries and send them to the configured backend.
+    pub(crate) fn flush(&self) {
+        // fetch and serialize the log streams
+        // take the buffered streams out of the lock, so logging can continue while we serialize
+        // the streams
+        let (streams, _) = self.log_stream.lock().take();
+        self.space_available.notify_all();
+
+        // each tenant requires a separate request
+        for (tenant, streams) in split_by_tenant(streams) {
+            match self.max_batch_bytes {
+                Some(max_batch_bytes) => {
+                    for streams in split_by_size(streams, max_batch_bytes) {
+                        self.send(tenant.clone(), streams);
+                    }
+                }
+                None => self.send(tenant, streams),
+            }
+        }
+    }
+
+    /// Serialize the streams of a single tenant and send them to the configured backend.
+    ///
+    /// If Loki rejects the batch as too large (`413 Payload Too Large`), it is split in half and
+    /// both parts are sent separately.
+    fn send(&self, tenant: Option<String>, streams: Vec<Stream>) {
+        let entries = streams.iter().map(|stream| stream.values.len()).sum();
+        let batch = match self.encoder.encode(streams, tenant) {
+            Ok(batch) => batch,
+            Err(error) => {
+                self.undelivered.fetch_add(entries, Ordering::Relaxed);
+                self.error_handler
+                    .report(&error, DroppedBatchInfo { entries, bytes: 0 });
+                return;
+            }
+        };
+
+        let mut batches = VecDeque::from([batch]);
+        while let Some(batch) = batches.pop_front() {
+            // the backend consumes the batch, so a copy (sharing its payload) is kept for settling
+            let result = self.backend.send(batch.clone());
+            let settlement = settle_delivery(
+                result,
+                batch,
+                &self.encoder,
+                self.spool.as_deref(),
+                &self.error_handler,
+            );
+            self.undelivered
+                .fetch_add(settlement.dropped, Ordering::Relaxed);
+            // the parts are sent before all other batches to keep the order of the entries
+            for part in settlement.resend.into_iter().rev() {
+                batches.push_front(part);
+            }
+        }
+    }
+
+    /// Get the time until the oldest buffered log entry is older than `interval`.
+    ///
+    /// If the buffer is empty, `interval` is returned since a new entry cannot become due any
+    /// earlier than that.
+    pub(crate) fn flush_due_in(&self, interval: Duration) -> Duration {
+        match self.log_stream.lock().oldest_entry {
+            Some(oldest_entry) => interval.saturating_sub(oldest_entry.elapsed()),
+            None => interval,
+        }
+    }
+}
+
+/// The [`Encoder`] serializes (and compresses) log streams into [`Batch`]es which can be sent to
+/// Loki.
+#[derive(Clone, Copy)]
+pub(crate) struct Encoder {
+    /// The function which is used to serialize the log streams
+    pub(crate) serializer: SerializationFn,
+    /// The format which is produced by the [`Encoder::serializer`]
+    pub(crate) format: SerializationFormat,
+    /// The compression which is applied to the serialized log streams
+    pub(crate) compression: Compression,
+}
+
+impl Encoder {
+    /// Serialize (and compress) the streams of a single tenant into a [`Batch`].
+    pub(crate) fn encode(
+        &self,
+        streams: Vec<Stream>,
+        tenant: Option<String>,
+    ) -> Result<Batch, FenrirError> {
+        let entries = streams.iter().map(|stream| stream.values.len()).sum();
+        let payload = (self.serializer)(&Streams { streams: &streams })?;
+
+        // formats which are already encoded (e.g. protobuf) are not compressed any further
+        let (payload, content_encoding) = match self.format.content_encoding() {
+            Some(content_encoding) => (payload, Some(content_encoding)),
+            None => (
+                self.compression.compress(payload)?,
+                self.compression.content_encoding(),
+            ),
+        };
+        Ok(Batch {
+            payload: Arc::new(payload),
+            content_type: self.format.content_type(),
+            content_encoding,
+            entries,
+            tenant,
+            streams: Some(Arc::new(streams)),
+            receipt: None,
+        })
+    }
+}
+
+/// The [`BufferLimits`] restrict the number and the size of the log entries which can be buffered
+/// by a [`LogShipper`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BufferLimits {
+    /// The maximum number of buffered log entries
+    pub(crate) max_entries: Option<usize>,
+    /// The maximum size of all buffered log lines in bytes
+    pub(crate) max_bytes: Option<usize>,
+    /// What happens to new log entries if the buffer is full
+    pub(crate) policy: OverflowPolicy,
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        BufferLimits {
+            max_entries: None,
+            max_bytes: None,
+            policy: OverflowPolicy::DropNewest,
+        }
+    }
+}
+
+impl BufferLimits {
+    /// Check if a log line with `size` bytes can be added to the `buffer` without exceeding the
+    /// limits
+    fn fits(&self, buffer: &LogBuffer, size: usize) -> bool {
+        let entries_fit = match self.max_entries {
+            Some(max_entries) => buffer.entries < max_entries,
+            None => true,
+        };
+        let bytes_fit = match self.max_bytes {
+            Some(max_bytes) => buffer.bytes + size <= max_bytes,
+            None => true,
+        };
+        entries_fit && bytes_fit
+    }
+
+    /// Check if a log line with `size` bytes could be added to an empty buffer
+    fn fits_empty_buffer(&self, size: usize) -> bool {
+        match self.max_bytes {
+            Some(max_bytes) => size <= max_bytes,
+            None => true,
+        }
+    }
+}
+
+/// Check if the name can be used as a label by Loki (`[a-zA-Z_][a-zA-Z0-9_]*`)
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_valid =
+        matches!(chars.next(), Some(first) if first.is_ascii_alphabetic() || first == '_');
+    starts_valid
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+        && name.len() <= MAX_LABEL_NAME_LENGTH
+}
+
+/// Turn the name into a valid label name by replacing all invalid characters with `_` (e.g.
+/// `http.method` becomes `http_method`) and truncating it to the maximum length
+// only used for structured logging
+#[cfg_attr(not(feature = "structured_logging"), allow(dead_code))]
+fn sanitize_label_name(name: &str) -> String {
+    let mut sanitized = name
+        .chars()
+        .map(|char| match char.is_ascii_alphanumeric() {
+            true => char,
+            false => '_',
+        })
+        .collect::<String>();
+    if !sanitized.starts_with(|first: char| !first.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized.truncate(MAX_LABEL_NAME_LENGTH);
+    sanitized
+}
+
+/// Truncate the label value to the maximum length (without splitting a character)
+// only used for structured logging
+#[cfg_attr(not(feature = "structured_logging"), allow(dead_code))]
+fn truncate_label_value(mut value: String) -> String {
+    if value.len() > MAX_LABEL_VALUE_LENGTH {
+        let mut length = MAX_LABEL_VALUE_LENGTH;
+        while !value.is_char_boundary(length) {
+            length -= 1;
+        }
+        value.truncate(length);
+    }
+    value
+}
+
+/// Append the path of the push API to the path of the endpoint (instead of replacing it, like
+/// [`Url::join`] does for absolute paths)
+fn push_url(endpoint: &Url, push_path: &str) -> Result<Url, url::ParseError> {
+    let mut base = endpoint.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join(push_path.trim_start_matches('/'))
+}
+
+/// Split the streams into groups which belong to the same tenant (keeping their order)
+fn split_by_tenant(streams: Vec<Stream>) -> Vec<(Option<String>, Vec<Stream>)> {
+    let mut groups: Vec<(Option<String>, Vec<Stream>)> = Vec::new();
+    for stream in streams {
+        match groups
+            .iter_mut()
+            .find(|(tenant, _)| *tenant == stream.tenant)
+        {
+            Some((_, streams)) => streams.push(stream),
+            None => groups.push((stream.tenant.clone(), vec![stream])),
+        }
+    }
+    groups
+}
+
+/// Split the streams into chunks whose approximate serialized size does not exceed `max_size`
+/// (keeping the order of the streams and their entries). Log entries which are larger than
+/// `max_size` on their own end up in a chunk of their own.
+fn split_by_size(streams: Vec<Stream>, max_size: usize) -> Vec<Vec<Stream>> {
+    let mut chunks = Vec::new();
+    let mut chunk: Vec<Stream> = Vec::new();
+    let mut chunk_size = 0;
+    for stream in streams {
+        let labels_size = stream.labels_size();
+        let mut part = stream.with_values(VecDeque::new());
+        for entry in stream.values {
+            // the labels have to be sent again for each chunk containing entries of the stream
+            let mut added = match part.values.is_empty() {
+                true => labels_size + entry.approximate_size(),
+                false => entry.approximate_size(),
+            };
+            if chunk_size + added > max_size && (!chunk.is_empty() || !part.values.is_empty()) {
+                if !part.values.is_empty() {
+                    let next = part.with_values(VecDeque::new());
+                    chunk.push(std::mem::replace(&mut part, next));
+                }
+                chunks.push(std::mem::take(&mut chunk));
+                chunk_size = 0;
+                added = labels_size + entry.approximate_size();
+            }
+            part.values.push_back(entry);
+            chunk_size += added;
+        }
+        if !part.values.is_empty() {
+            chunk.push(part);
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Split the streams of a batch which was rejected as too large into two parts of about the same
+/// size
+fn split_in_half(streams: &[Stream]) -> Vec<Vec<Stream>> {
+    let size = streams.iter().map(Stream::approximate_size).sum::<usize>();
+    split_by_size(streams.to_vec(), size / 2)
+}
+
+/// The [`Settlement`] of an attempt to deliver a [`Batch`] (see [`settle_delivery`])
+pub(crate) struct Settlement {
+    /// The parts of the batch which have to be sent again, since Loki rejected it as too large
+    pub(crate) resend: Vec<Batch>,
+    /// The number of log entries which were dropped (and already reported)
+    pub(crate) dropped: usize,
+}
+
+/// Decide what happens to the `batch` after the attempt to deliver it finished with `result`.
+///
+/// If Loki rejected the batch as too large (`413 Payload Too Large`), it is split in half and
+/// both parts are returned, so they can be sent again. Batches which might be delivered later on
+/// are stored in the `spool` (if any), all other ones are dropped and reported to the
+/// `error_handler`. This is shared by all code paths which send batches created by [`Fenrir`].
+pub(crate) fn settle_delivery(
+    result: Result<(), FenrirError>,
+    batch: Batch,
+    encoder: &Encoder,
+    spool: Option<&Spool>,
+    error_handler: &ErrorHandler,
+) -> Settlement {
+    let mut settlement = Settlement {
+        resend: Vec::new(),
+        dropped: 0,
+    };
+    let error = match result {
+        Ok(()) => return settlement,
+        Err(error) => error,
+    };
+
+    // only batches created by Fenrir itself know their streams and can be split
+    if let (Some(413), true, Some(streams)) = (error.status(), batch.entries > 1, &batch.streams) {
+        for part in split_in_half(streams) {
+            let entries = part.iter().map(|stream| stream.values.len()).sum();
+            match encoder.encode(part, batch.tenant.clone()) {
+                Ok(part) => settlement.resend.push(part),
+                Err(error) => {
+                    settlement.dropped += entries;
+                    error_handler.report(&error, DroppedBatchInfo { entries, bytes: 0 });
+                }
+            }
+        }
+        return settlement;
+    }
+
+    // batches which might be delivered later on are kept in the spool
+    let error = match spool {
+        Some(spool) if is_retryable(&error) => match spool.store(&batch) {
+            Ok(()) => return settlement,
+            Err(spool_error) => spool_error,
+        },
+        _ => error,
+    };
+    settlement.dropped = batch.entries;
+    error_handler.report(
+        &error,
+        DroppedBatchInfo {
+            entries: batch.entries,
+            bytes: batch.payload.len(),
+        },
+    );
+    settlement
+}
+
+/// The [`FenrirBuilder`] struct is used to create a new instance of [`Fenrir`] using the builder pattern.
+///
+/// This should make it easier and more intuitive (at least I hope) to use the crate without referring
+/// to the documentation all the time.
+pub struct FenrirBuilder {
+    /// The loki `endpoint` which is used to send log information to
+    endpoint: Url,
+    /// The path of the push API relative to the `endpoint`
+    push_path: String,
+    /// The `authentication` method to use when sending the log messages to the remote endpoint
+    authentication: AuthenticationMethod,
+    /// The `network_backend` which should be used for the network requests
+    network_backend: NetworkingBackend,
+    /// The `serialization_format´ used for the logging messages
+    serialization_format: SerializationFormat,
+    /// The `compression` which is applied to the serialized logging messages
+    compression: Compression,
+    /// A map of additional tags which should be attached to all log messages
+    additional_tags: HashMap<String, String>,
+    /// The `credentials` to use to authenticate against the remote `endpoint`
+    credentials: String,
+    /// The `filter` which decides which logging messages are sent to Loki
+    filter: Filter,
+    /// A backend supplied by the user which is used instead of the `network_backend`
+    custom_backend: Option<Box<dyn FenrirBackend>>,
+    /// Targets whose logging messages should never be sent to Loki (in addition to the ones
+    /// excluded by the network backend)
+    excluded_targets: Vec<String>,
+    /// Targets which should not be excluded even if the network backend excludes them
+    allowed_targets: Vec<String>,
+    /// If set to `true`, the logging level is included as a tag
+    include_level: bool,
+    /// If set to `true,` the logging framework (`fenrir-rs`) is included as a tag
+    include_framework: bool,
+    /// A runtime handle to the tokio runtime, if it is used
+    #[cfg(feature = "async-tokio")]
+    runtime: Option<tokio::runtime::Handle>,
+    #[cfg(not(feature = "async-tokio"))]
+    runtime: Option<()>,
+    /// Number of log messages after which to flush all outstanding messages to Loki.
+    /// Defaults to 100.
+    /// Must be greater than 0.
+    flush_threshold: usize,
+    /// Skip a log message if its serialized representation is larger than this value in bytes.
+    /// Defaults to None, which means no limit.
+    /// If set, must be greater than 0.
+    max_message_size: Option<usize>,
+    /// The format of the log lines sent to Loki.
+    /// Defaults to the JSON format if the `json-log-fmt` feature is enabled and to the plain
+    /// message otherwise.
+    line_format: LineFormat,
+    /// The maximum (approximate) size of a single batch sent to Loki in bytes.
+    /// Defaults to None, which means no limit.
+    max_batch_bytes: Option<usize>,
+    /// Flush the buffered log messages as soon as the oldest one is older than this interval.
+    /// Defaults to None, which means logs are only flushed when `flush_threshold` is reached.
+    flush_interval: Option<Duration>,
+    /// If set to `true`, the buffered log messages are flushed by a background worker thread
+    /// instead of the logging thread
+    background_dispatch: bool,
+    /// The directory and the maximum size (in bytes) of the spool for batches which could not be
+    /// delivered. Defaults to None, which means that such batches are dropped.
+    spool: Option<(PathBuf, usize)>,
+    /// The limits of the buffer for log messages which were not sent yet.
+    /// Defaults to no limits at all.
+    buffer_limits: BufferLimits,
+    /// The maximum time a single request to Loki may take.
+    /// Defaults to 10 seconds.
+    request_timeout: Duration,
+    /// The maximum number of idle connections to Loki which are kept open for reuse.
+    /// Defaults to 3.
+    connection_pool_size: usize,
+    /// The time after which an idle connection to Loki is closed.
+    /// Defaults to 15 seconds.
+    idle_connection_timeout: Duration,
+    /// The policy which decides if and when failed requests to Loki are retried
+    retry_policy: RetryPolicy,
+    /// Additional HTTP headers which are sent with each request to Loki
+    headers: Vec<(String, String)>,
+    /// The provider which supplies the credentials for each request (instead of the static ones)
+    credential_provider: Option<Provider>,
+    /// The tenant (`X-Scope-OrgID`) the log messages are sent to
+    tenant_id: Option<String>,
+    /// The key of the structured logging value which selects the tenant of a log message
+    #[cfg(feature = "structured_logging")]
+    tenant_key: Option<String>,
+    /// The keys of the structured logging values which are attached as structured metadata
+    #[cfg(feature = "structured_logging")]
+    metadata_keys: MetadataKeys,
+    /// The policy which limits the labels attached using structured logging
+    #[cfg(feature = "structured_logging")]
+    label_policy: Option<LabelPolicy>,
+    /// The callback which is called if log messages could not be delivered
+    error_callback: Option<Box<ErrorCallback>>,
+    /// If set to `true`, Fenrir panics if log messages could not be delivered
+    panic_on_error: bool,
+    /// The problems found in the configuration which are reported as soon as the error handler
+    /// was created (e.g. invalid filter directives read from the environment)
+    configuration_warnings: Vec<FenrirError>,
+}
+
+impl FenrirBuilder {
+    /// Set the endpoint which should be used for sending the log messages to.
     ///
     /// # Example
     /// ```
@@ -299,6 +1441,28 @@ impl FenrirBuilder {
         self
     }
 
+    /// Set the path of the push API relative to the configured endpoint. Defaults to
+    /// `loki/api/v1/push`, but other gateways (like an HTTP source of Vector) might expect the log
+    /// messages at a different path.
+    ///
+    /// The path is always appended to the path of the endpoint, so an endpoint like
+    /// `https://gateway.example.com/loki-eu` sends the log messages to
+    /// `https://gateway.example.com/loki-eu/loki/api/v1/push`.
+    ///
+    /// # Example
+    /// ```
+    /// use url::Url;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .endpoint(Url::parse("https://vector.example.com").unwrap())
+    ///     .push_path("ingest/loki");
+    /// ```
+    pub fn push_path(mut self, path: &str) -> FenrirBuilder {
+        self.push_path = path.to_string();
+        self
+    }
+
     /// Set the network backend which should be used to communicate with a Loki endpoint.
     ///
     /// # Example
@@ -314,8 +1478,36 @@ impl FenrirBuilder {
         self
     }
 
+    /// Use a custom implementation of the [`FenrirBackend`] trait to communicate with a Loki
+    /// endpoint. The network backend selected with [`FenrirBuilder::network`] as well as the
+    /// endpoint and the authentication settings of this builder are ignored in this case.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::{Batch, Fenrir, FenrirBackend, FenrirError};
+    ///
+    /// struct StderrBackend;
+    ///
+    /// impl FenrirBackend for StderrBackend {
+    ///     fn send(&self, batch: Batch) -> Result<(), FenrirError> {
+    ///         eprintln!("{}", String::from_utf8_lossy(batch.payload()));
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .custom_backend(Box::new(StderrBackend));
+    /// ```
+    pub fn custom_backend(mut self, backend: Box<dyn FenrirBackend>) -> FenrirBuilder {
+        self.custom_backend = Some(backend);
+        self
+    }
+
     /// Ensure our client uses the supplied credentials for authentication against the remote endpoint.
     ///
+    /// The `username` and `password` are ignored for [`AuthenticationMethod::Bearer`] since the
+    /// token is part of the method itself (see [`FenrirBuilder::bearer_token`] for a shorthand).
+    ///
     /// # Example
     /// ```
     /// use url::Url;
@@ -338,12 +1530,83 @@ impl FenrirBuilder {
                     general_purpose::STANDARD.encode(format!("{}:{}", username, password));
                 self.credentials = b64_credentials;
             }
+            AuthenticationMethod::Bearer(ref token) => self.credentials = token.clone(),
         }
 
         self.authentication = method;
         self
     }
 
+    /// Authenticate against the remote endpoint using the supplied bearer token
+    /// (`Authorization: Bearer <token>`).
+    ///
+    /// This is a shorthand for [`FenrirBuilder::with_authentication`] using
+    /// [`AuthenticationMethod::Bearer`].
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .bearer_token("token");
+    /// ```
+    pub fn bearer_token(self, token: &str) -> FenrirBuilder {
+        self.with_authentication(
+            AuthenticationMethod::Bearer(token.to_string()),
+            String::new(),
+            String::new(),
+        )
+    }
+
+    /// Query the supplied provider for the credentials before sending log messages to Loki. This
+    /// replaces the credentials configured with [`FenrirBuilder::with_authentication`] and allows
+    /// using tokens which expire while the application is running.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::{Duration, SystemTime};
+    /// use fenrir_rs::{Credential, FenrirError, Fenrir};
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .credential_provider(|| -> Result<Credential, FenrirError> {
+    ///         Ok(Credential::bearer("token")
+    ///             .expires_at(SystemTime::now() + Duration::from_secs(3600)))
+    ///     });
+    /// ```
+    pub fn credential_provider<P: CredentialProvider>(mut self, provider: P) -> FenrirBuilder {
+        self.credential_provider = Some(Provider::Sync(Arc::new(provider)));
+        self
+    }
+
+    /// Query the supplied asynchronous provider for the credentials before sending log messages to
+    /// Loki (see [`FenrirBuilder::credential_provider`]).
+    ///
+    /// Asynchronous providers can only be used with the `Reqwest` backend. For all other backends
+    /// each attempt to send log messages fails with [`FenrirError::Configuration`].
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::{AsyncCredentialProvider, Credential, CredentialFuture, Fenrir};
+    ///
+    /// struct TokenEndpoint;
+    ///
+    /// impl AsyncCredentialProvider for TokenEndpoint {
+    ///     fn credential(&self) -> CredentialFuture<'_> {
+    ///         Box::pin(async { Ok(Credential::bearer("token")) })
+    ///     }
+    /// }
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .async_credential_provider(TokenEndpoint);
+    /// ```
+    pub fn async_credential_provider<P: AsyncCredentialProvider>(
+        mut self,
+        provider: P,
+    ) -> FenrirBuilder {
+        self.credential_provider = Some(Provider::Async(Arc::new(provider)));
+        self
+    }
+
     /// Select the format which should be used for serializing the logging messages before sending
     /// them to the configured Loki endpoint.
     ///
@@ -360,365 +1623,2492 @@ impl FenrirBuilder {
         self
     }
 
-    /// Add an additional tag to all logging messages which are sent to Loki.
-    /// This can be used to add additional information to the log messages which can be used for
-    /// filtering in Loki.
+    /// Select the compression which should be applied to the serialized logging messages before
+    /// sending them to the configured Loki endpoint.
+    ///
+    /// # Note
+    /// The compression is not applied if the selected [`SerializationFormat`] is already
+    /// compressed (e.g. [`SerializationFormat::Protobuf`]).
     ///
     /// # Example
     /// ```
-    /// use url::Url;
-    /// use fenrir_rs::Fenrir;
+    /// use fenrir_rs::{Compression, Fenrir};
     ///
     /// let builder = Fenrir::builder()
-    ///     .tag("service", "service_name")
-    ///     .tag("environment", "production");
+    ///     .compression(Compression::None);
     /// ```
-    pub fn tag(mut self, name: &str, value: &str) -> FenrirBuilder {
-        self.additional_tags
-            .insert(name.to_string(), value.to_string());
+    pub fn compression(mut self, compression: Compression) -> FenrirBuilder {
+        self.compression = compression;
         self
     }
 
-    /// Ensure that a tag for the logging level of the logging message is included in each logging
-    /// message send to Loki.
+    /// Add a static HTTP header which is sent with each request to Loki (e.g. for passing a proxy
+    /// or a gateway). Adding a header with the same name again replaces its value.
+    ///
+    /// The headers set by the network backends themselves (like `Content-Type` or
+    /// `Authorization`) should not be set using this method.
+    ///
+    /// # Panics
+    /// This method will panic if the name or the value is not valid for an HTTP header.
     ///
     /// # Example
     /// ```
-    /// use url::Url;
     /// use fenrir_rs::Fenrir;
     ///
     /// let builder = Fenrir::builder()
-    ///     .include_level();
+    ///     .header("X-Gateway-Key", "secret");
     /// ```
-    pub fn include_level(mut self) -> FenrirBuilder {
-        self.include_level = true;
+    pub fn header(mut self, name: &str, value: &str) -> FenrirBuilder {
+        let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if name.is_empty() || !name.chars().all(is_token_char) {
+            panic!("'{}' is not a valid name for an HTTP header", name);
+        }
+        if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+            panic!("The value of the HTTP header '{}' is not valid", name);
+        }
+        self.headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
         self
     }
 
-    /// Ensure that a tag for the used logging framework (`fenrir-rs`) is included in all send
-    /// logging messages.
+    /// Send all log messages to the supplied tenant of a multi-tenant Loki instance (using the
+    /// `X-Scope-OrgID` header).
     ///
     /// # Example
     /// ```
-    /// use url::Url;
     /// use fenrir_rs::Fenrir;
     ///
     /// let builder = Fenrir::builder()
-    ///     .include_framework();
+    ///     .tenant_id("team-a");
     /// ```
-    #[deprecated(
-        since = "0.4.1",
-        note = "This is not useful in general and can be achieved by using `tag` method."
-    )]
-    pub fn include_framework(mut self) -> FenrirBuilder {
-        self.include_framework = true;
+    pub fn tenant_id(mut self, tenant: &str) -> FenrirBuilder {
+        self.tenant_id = Some(tenant.to_string());
         self
     }
 
-    /// Set the runtime handle to the tokio runtime which should be used for sending the log messages.
+    /// Select the tenant of a single log message using the structured logging value with the
+    /// supplied `key`. The value is not attached as a label to the log message and log messages
+    /// without such a value are sent to the tenant configured with [`FenrirBuilder::tenant_id`]
+    /// (if any).
+    ///
+    /// Log messages of different tenants are sent using separate requests.
     ///
     /// # Example
     /// ```
-    /// use tokio::runtime::Handle;
     /// use fenrir_rs::Fenrir;
     ///
-    /// # #[tokio::main]
-    /// # async fn main() {
-    ///   let handle = Handle::current();
-    ///   let builder = Fenrir::builder()
-    ///     .tokio_rt_handle(handle);
-    /// # }
-    #[cfg(feature = "async-tokio")]
-    pub fn tokio_rt_handle(mut self, rt_handle: tokio::runtime::Handle) -> FenrirBuilder {
-        self.runtime = Some(rt_handle);
+    /// let builder = Fenrir::builder()
+    ///     .tenant_id("default")
+    ///     .tenant_key("tenant");
+    ///
+    /// // log::info!(tenant = "team-b"; "This message is sent to the tenant team-b");
+    /// ```
+    #[cfg(feature = "structured_logging")]
+    pub fn tenant_key(mut self, key: &str) -> FenrirBuilder {
+        self.tenant_key = Some(key.to_string());
         self
     }
 
-    /// Fetch the active runtime's handle and use it as the designated runtime.
+    /// Attach the structured logging values with the supplied keys as
+    /// [structured metadata](https://grafana.com/docs/loki/latest/get-started/labels/structured-metadata/)
+    /// instead of labels. This should be used for values with a high cardinality (like request
+    /// IDs), since each distinct label set creates a new stream in Loki.
     ///
-    /// # Note
-    /// This method will panic if called outside the context of a Tokio 1.x runtime.
+    /// The keys are matched after they were turned into valid label names (e.g. `http_method` for
+    /// `http.method`), the same way as the keys of a [`LabelPolicy`]. If several keys of a log
+    /// message are turned into the same name, only the first one (in alphabetical order) is
+    /// attached and the others are reported to the callback set with [`FenrirBuilder::on_error`]
+    /// (using [`FenrirError::LabelRejected`]).
+    ///
+    /// Structured metadata requires Loki 3.0 or newer. This replaces the keys configured using
+    /// [`FenrirBuilder::label_keys`].
     ///
     /// # Example
     /// ```
-    /// use tokio::runtime::Handle;
     /// use fenrir_rs::Fenrir;
     ///
-    /// # #[tokio::main]
-    /// # async fn main() {
-    ///   let builder = Fenrir::builder()
-    ///     .tokio_rt_handle_current();
-    /// # }
-    #[cfg(feature = "async-tokio")]
-    pub fn tokio_rt_handle_current(mut self) -> FenrirBuilder {
-        self.runtime = Some(tokio::runtime::Handle::current());
+    /// let builder = Fenrir::builder()
+    ///     .structured_metadata_keys(&["request_id", "trace_id"]);
+    ///
+    /// // log::info!(request_id = "4711"; "The request_id is not attached as a label");
+    /// ```
+    #[cfg(feature = "structured_logging")]
+    pub fn structured_metadata_keys(mut self, keys: &[&str]) -> FenrirBuilder {
+        self.metadata_keys =
+            MetadataKeys::Selected(keys.iter().map(|key| key.to_string()).collect());
         self
     }
 
-    /// Configure the number of messages which should be buffered before sending them all to Loki.
+    /// Attach only the structured logging values with the supplied keys as labels and all other
+    /// values as structured metadata (see [`FenrirBuilder::structured_metadata_keys`]). The keys
+    /// are matched after they were turned into valid label names (e.g. `http_method` for
+    /// `http.method`).
     ///
-    /// # Panics
-    /// This method will panic if the supplied value is 0.
+    /// Structured metadata requires Loki 3.0 or newer. This replaces the keys configured using
+    /// [`FenrirBuilder::structured_metadata_keys`].
     ///
     /// # Example
     /// ```
     /// use fenrir_rs::Fenrir;
     ///
     /// let builder = Fenrir::builder()
-    ///    .flush_threshold(100);
+    ///     .label_keys(&["service", "environment"]);
     /// ```
-    pub fn flush_threshold(mut self, size: usize) -> FenrirBuilder {
-        if size == 0 {
-            panic!("Buffer size must be greater than 0");
-        }
-        self.flush_threshold = size;
+    #[cfg(feature = "structured_logging")]
+    pub fn label_keys(mut self, keys: &[&str]) -> FenrirBuilder {
+        self.metadata_keys =
+            MetadataKeys::AllExcept(keys.iter().map(|key| key.to_string()).collect());
         self
     }
 
-    /// Configure the maximum size of a single message, in bytes, before it is dropped.
-    /// This is useful for avoiding network issues when sending large messages on slow or unreliable networks.
-    /// Defaults to None, which means that no limit is enforced.
+    /// Limit the labels which are attached using structured logging (see [`LabelPolicy`]).
+    /// Defaults to no limits at all.
+    ///
+    /// This protects Loki from an accidentally unbounded label (like a user ID), which would create
+    /// a new stream for each of its values.
     ///
     /// # Panics
-    /// This method will panic if the supplied value is 0.
+    /// This method will panic if the `max_values_per_key` of the supplied policy are 0 or its
+    /// `window` is zero.
     ///
     /// # Example
     /// ```
-    /// use fenrir_rs::Fenrir;
+    /// use fenrir_rs::{Fenrir, LabelKeys, LabelPolicy};
     ///
-    /// let builder = Fenrir::builder()
-    ///    .max_message_size(Some(1048576));
+    /// let builder = Fenrir::builder().label_policy(
+    ///     LabelPolicy::new()
+    ///         .with_keys(LabelKeys::deny(&["user_id"]))
+    ///         .with_max_labels(10)
+    ///         .with_max_values_per_key(100),
+    /// );
     /// ```
-    pub fn max_message_size(mut self, size: Option<usize>) -> FenrirBuilder {
-        if size == Some(0) {
-            panic!("Max message size must be greater than 0");
+    #[cfg(feature = "structured_logging")]
+    pub fn label_policy(mut self, policy: LabelPolicy) -> FenrirBuilder {
+        if policy.max_values_per_key == Some(0) {
+            panic!("The label policy must allow at least one value per key");
         }
-        self.max_message_size = size;
+        if policy.window.is_zero() {
+            panic!("The window of the label policy must be greater than 0");
+        }
+        self.label_policy = Some(policy);
         self
     }
 
-    /// Create a new `Fenrir` instance with the parameters supplied to this struct before calling this method.
+    /// Add an additional tag to all logging messages which are sent to Loki.
+    /// This can be used to add additional information to the log messages which can be used for
+    /// filtering in Loki.
     ///
-    /// Before creating a new instance, the supplied parameters are validated (in contrast to [`FenrirBuilder::build`]
-    /// which does not validate the supplied parameters).
+    /// # Panics
+    /// This method will panic if the name is not a valid label name for Loki (it has to match
+    /// `[a-zA-Z_][a-zA-Z0-9_]*` and must not be longer than 1024 bytes) or if the value is longer
+    /// than 2048 bytes.
+    ///
+    /// # Example
+    /// ```
+    /// use url::Url;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .tag("service", "service_name")
+    ///     .tag("environment", "production");
+    /// ```
+    pub fn tag(mut self, name: &str, value: &str) -> FenrirBuilder {
+        if !is_valid_label_name(name) {
+            panic!("The tag name {:?} is not a valid label name", name);
+        }
+        if value.len() > MAX_LABEL_VALUE_LENGTH {
+            panic!("The value of the tag {} is too long", name);
+        }
+        self.additional_tags
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Set the maximum level of the logging messages which should be sent to Loki.
+    ///
+    /// This level applies to all targets for which no specific level was set using
+    /// [`FenrirBuilder::level_for`]. Defaults to [`LevelFilter::Trace`], so only the global maximum
+    /// level of the `log` crate (or the level set in frameworks like `fern`) applies.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    /// use log::LevelFilter;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .max_level(LevelFilter::Info);
+    /// ```
+    pub fn max_level(mut self, level: LevelFilter) -> FenrirBuilder {
+        self.filter.max_level = level;
+        self
+    }
+
+    /// Set the maximum level of the logging messages of a specific target (and all of its
+    /// sub-modules) which should be sent to Loki.
+    ///
+    /// If several targets match a logging message, the most specific one is used. Setting the level
+    /// for the same target twice replaces the previously set level.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    /// use log::LevelFilter;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .max_level(LevelFilter::Debug)
+    ///     .level_for("hyper", LevelFilter::Warn)
+    ///     .level_for("my_crate::db", LevelFilter::Trace);
+    /// ```
+    pub fn level_for(mut self, target: &str, level: LevelFilter) -> FenrirBuilder {
+        self.filter.set_level_for(target, level);
+        self
+    }
+
+    /// Configure the filter for the logging messages using directives in the format known from
+    /// `env_logger` (e.g. `info,my_crate::db=debug,hyper=off`).
+    ///
+    /// Each comma-separated directive is either a level (which sets the maximum level like
+    /// [`FenrirBuilder::max_level`]), a target (which enables all levels for this target) or a
+    /// `target=level` pair (like [`FenrirBuilder::level_for`]). The directives can be followed by a
+    /// slash and a filter which the logging messages have to match (e.g. `info/connection`). If the
+    /// `regex` feature is enabled, this filter is a regular expression. Otherwise, the messages
+    /// have to contain it.
     ///
     /// # Panics
-    /// The method will panic if one or more of the supplied parameters are not valid or seem to have
-    /// unintended values.
+    /// This method will panic if the directives cannot be parsed.
     ///
     /// # Example
-    /// ```should_panic
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .filter_directives("info,my_crate::db=debug,hyper=off");
+    /// ```
+    pub fn filter_directives(mut self, directives: &str) -> FenrirBuilder {
+        if let Err(error) = self.filter.parse_directives(directives) {
+            panic!("Invalid filter directives '{}': {}", directives, error);
+        }
+        self
+    }
+
+    /// Configure the filter for the logging messages using the directives stored in the supplied
+    /// environment variable (e.g. `FENRIR_LOG`).
+    ///
+    /// See [`FenrirBuilder::filter_directives`] for the format of the directives. If the
+    /// environment variable is not set, the filter is not changed. Unlike
+    /// [`FenrirBuilder::filter_directives`], invalid directives do not cause a panic, but are
+    /// ignored (like `env_logger` does). Each of them is reported to the callback set with
+    /// [`FenrirBuilder::on_error`] (using [`FenrirError::Configuration`]) as soon as the instance
+    /// is built.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .max_level(log::LevelFilter::Info)
+    ///     .filter_from_env("FENRIR_LOG");
+    /// ```
+    pub fn filter_from_env(mut self, variable: &str) -> FenrirBuilder {
+        if let Ok(directives) = std::env::var(variable) {
+            for error in self.filter.parse_directives_lossy(&directives) {
+                self.configuration_warnings
+                    .push(FenrirError::Configuration(format!(
+                        "ignoring the invalid filter directive in {}: {}",
+                        variable, error
+                    )));
+            }
+        }
+        self
+    }
+
+    /// Never send logging messages of the supplied target (and all of its sub-modules) to Loki.
+    ///
+    /// Each network backend already excludes the targets of its networking library (e.g. `ureq`
+    /// or `reqwest`, `hyper` and `h2`), since their logging messages would create an infinite
+    /// loop. This method can be used to exclude further targets. Targets are matched on crate
+    /// and module boundaries, so excluding `hyper` does not exclude `hyper_util`.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .exclude_target("my_http_client");
+    /// ```
+    pub fn exclude_target(mut self, target: &str) -> FenrirBuilder {
+        self.allowed_targets.retain(|allowed| allowed != target);
+        self.excluded_targets.push(target.to_string());
+        self
+    }
+
+    /// Send logging messages of the supplied target to Loki, even if it is excluded by default by
+    /// the network backend.
+    ///
+    /// # Note
+    /// Only use this for targets which do not log anything while sending logging messages to Loki.
+    /// Otherwise, this might result in an infinite loop.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .allow_target("rustls");
+    /// ```
+    pub fn allow_target(mut self, target: &str) -> FenrirBuilder {
+        self.excluded_targets.retain(|excluded| excluded != target);
+        self.allowed_targets.push(target.to_string());
+        self
+    }
+
+    /// Ensure that a tag for the logging level of the logging message is included in each logging
+    /// message send to Loki.
+    ///
+    /// # Example
+    /// ```
     /// use url::Url;
-    /// use fenrir_rs::{Fenrir, NetworkingBackend, SerializationFormat};
+    /// use fenrir_rs::Fenrir;
     ///
-    /// let fenrir = Fenrir::builder()
-    ///     .endpoint(Url::parse("https://loki.example.com").unwrap())
-    ///     .network(NetworkingBackend::None)
-    ///     .format(SerializationFormat::None)
-    ///     .build_with_validation();
+    /// let builder = Fenrir::builder()
+    ///     .include_level();
     /// ```
-    pub fn build_with_validation(self) -> Fenrir {
-        // panic if no network backend was selected
-        if self.network_backend == NetworkingBackend::None {
-            panic!(
-                "You have to select a `NetworkingBackend` before creating an instance of `Fenrir`"
+    pub fn include_level(mut self) -> FenrirBuilder {
+        self.include_level = true;
+        self
+    }
+
+    /// Ensure that a tag for the used logging framework (`fenrir-rs`) is included in all send
+    /// logging messages.
+    ///
+    /// # Example
+    /// ```
+    /// use url::Url;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .include_framework();
+    /// ```
+    #[deprecated(
+        since = "0.4.1",
+        note = "This is not useful in general and can be achieved by using `tag` method."
+    )]
+    pub fn include_framework(mut self) -> FenrirBuilder {
+        self.include_framework = true;
+        self
+    }
+
+    /// Set the runtime handle to the tokio runtime which should be used for sending the log messages.
+    ///
+    /// # Example
+    /// ```
+    /// use tokio::runtime::Handle;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    ///   let handle = Handle::current();
+    ///   let builder = Fenrir::builder()
+    ///     .tokio_rt_handle(handle);
+    /// # }
+    #[cfg(feature = "async-tokio")]
+    pub fn tokio_rt_handle(mut self, rt_handle: tokio::runtime::Handle) -> FenrirBuilder {
+        self.runtime = Some(rt_handle);
+        self
+    }
+
+    /// Fetch the active runtime's handle and use it as the designated runtime.
+    ///
+    /// # Note
+    /// This method will panic if called outside the context of a Tokio 1.x runtime.
+    ///
+    /// # Example
+    /// ```
+    /// use tokio::runtime::Handle;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    ///   let builder = Fenrir::builder()
+    ///     .tokio_rt_handle_current();
+    /// # }
+    #[cfg(feature = "async-tokio")]
+    pub fn tokio_rt_handle_current(mut self) -> FenrirBuilder {
+        self.runtime = Some(tokio::runtime::Handle::current());
+        self
+    }
+
+    /// Configure the number of messages which should be buffered before sending them all to Loki.
+    ///
+    /// # Panics
+    /// This method will panic if the supplied value is 0.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .flush_threshold(100);
+    /// ```
+    pub fn flush_threshold(mut self, size: usize) -> FenrirBuilder {
+        if size == 0 {
+            panic!("Buffer size must be greater than 0");
+        }
+        self.flush_threshold = size;
+        self
+    }
+
+    /// Configure the maximum size of a single message, in bytes, before it is dropped.
+    /// This is useful for avoiding network issues when sending large messages on slow or unreliable networks.
+    /// Defaults to None, which means that no limit is enforced.
+    ///
+    /// # Panics
+    /// This method will panic if the supplied value is 0.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .max_message_size(Some(1048576));
+    /// ```
+    pub fn max_message_size(mut self, size: Option<usize>) -> FenrirBuilder {
+        if size == Some(0) {
+            panic!("Max message size must be greater than 0");
+        }
+        self.max_message_size = size;
+        self
+    }
+
+    /// Configure the format of the log lines sent to Loki.
+    /// Defaults to `LineFormat::Json` if the `json-log-fmt` feature is enabled and to
+    /// [`LineFormat::Message`] otherwise (`LineFormat::Json` can be selected whenever the `json`
+    /// feature is enabled).
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::{Fenrir, LineFormat};
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .line_format(LineFormat::Logfmt);
+    /// ```
+    pub fn line_format(mut self, format: LineFormat) -> FenrirBuilder {
+        self.line_format = format;
+        self
+    }
+
+    /// Configure the maximum size of a single batch of log messages sent to Loki, in bytes.
+    /// Defaults to None, which means that batches are only limited by the `flush_threshold`.
+    ///
+    /// The size of the buffered log messages (including their labels) is estimated while they are
+    /// buffered and the buffer is flushed before the next message would exceed the limit. The
+    /// estimate is based on the uncompressed JSON representation, so the actual requests are
+    /// usually smaller. Log messages which are larger than the limit on their own are sent in a
+    /// batch of their own.
+    ///
+    /// Independent of this setting, batches which are rejected by Loki with `413 Payload Too
+    /// Large` are split in half and sent again. Custom backends (see
+    /// [`FenrirBuilder::custom_backend`]) only benefit from this if they return the error of the
+    /// request from [`FenrirBackend::send`].
+    ///
+    /// # Panics
+    /// This method will panic if the supplied value is 0.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .max_batch_bytes(1024 * 1024);
+    /// ```
+    pub fn max_batch_bytes(mut self, bytes: usize) -> FenrirBuilder {
+        if bytes == 0 {
+            panic!("Max batch bytes must be greater than 0");
+        }
+        self.max_batch_bytes = Some(bytes);
+        self
+    }
+
+    /// Configure an interval after which buffered log messages are flushed to Loki, even if the
+    /// `flush_threshold` was not reached yet.
+    ///
+    /// A background worker checks the age of the oldest buffered message and flushes the buffer
+    /// as soon as it is older than the supplied interval. For the `Reqwest` backend the worker is
+    /// a task on the configured tokio runtime, for all other backends it is a separate thread.
+    /// The worker is stopped when the [`Fenrir`] instance is dropped.
+    ///
+    /// # Panics
+    /// This method will panic if the supplied interval is zero.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .flush_interval(Duration::from_secs(5));
+    /// ```
+    pub fn flush_interval(mut self, interval: Duration) -> FenrirBuilder {
+        if interval.is_zero() {
+            panic!("Flush interval must be greater than 0");
+        }
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Flush the buffered log messages on a dedicated worker thread instead of the logging thread.
+    ///
+    /// By default, the log message which reaches the `flush_threshold` serializes and sends all
+    /// buffered log messages on the thread which created it. With the background dispatch, the
+    /// logging thread only adds its log message to the buffer, so the latency of logging does not
+    /// depend on the latency of Loki anymore. It is recommended to limit the size of the buffer
+    /// (see [`FenrirBuilder::max_buffered_entries`]) in this case.
+    ///
+    /// Explicitly flushing the log messages (e.g. using [`Fenrir::shutdown`]) still happens on the
+    /// calling thread.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .background_dispatch()
+    ///    .max_buffered_entries(10_000);
+    /// ```
+    pub fn background_dispatch(mut self) -> FenrirBuilder {
+        self.background_dispatch = true;
+        self
+    }
+
+    /// Configure the maximum number of log messages which are buffered until they are sent to
+    /// Loki. If the buffer is full, the configured [`OverflowPolicy`] is applied to new log
+    /// messages. Defaults to no limit.
+    ///
+    /// The value should be greater than the `flush_threshold`, otherwise log messages are dropped
+    /// before the buffer is flushed.
+    ///
+    /// # Panics
+    /// This method will panic if the supplied value is 0.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .max_buffered_entries(10_000);
+    /// ```
+    pub fn max_buffered_entries(mut self, entries: usize) -> FenrirBuilder {
+        if entries == 0 {
+            panic!("Max buffered entries must be greater than 0");
+        }
+        self.buffer_limits.max_entries = Some(entries);
+        self
+    }
+
+    /// Configure the maximum size (in bytes) of all log messages which are buffered until they
+    /// are sent to Loki. If the buffer is full, the configured [`OverflowPolicy`] is applied to new
+    /// log messages. Defaults to no limit.
+    ///
+    /// Only the size of the serialized log messages themselves is taken into account (not the
+    /// size of their labels).
+    ///
+    /// # Panics
+    /// This method will panic if the supplied value is 0.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .max_buffered_bytes(16 * 1024 * 1024);
+    /// ```
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> FenrirBuilder {
+        if bytes == 0 {
+            panic!("Max buffered bytes must be greater than 0");
+        }
+        self.buffer_limits.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Configure what happens to new log messages if the buffer is full (see
+    /// [`FenrirBuilder::max_buffered_entries`] and [`FenrirBuilder::max_buffered_bytes`]).
+    /// Defaults to [`OverflowPolicy::DropNewest`].
+    ///
+    /// Dropped log messages are reported to the callback set with [`FenrirBuilder::on_error`]
+    /// (using [`FenrirError::BufferFull`]) and counted by [`Fenrir::dropped_entries`]. The callback
+    /// must not log using the same [`Fenrir`] instance, since this could drop even more messages.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use fenrir_rs::{Fenrir, OverflowPolicy};
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .max_buffered_entries(10_000)
+    ///    .overflow_policy(OverflowPolicy::Block(Duration::from_millis(100)));
+    /// ```
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> FenrirBuilder {
+        self.buffer_limits.policy = policy;
+        self
+    }
+
+    /// Store batches which could not be delivered to Loki in the supplied directory, so they are
+    /// not lost if Loki is not reachable for a while. Defaults to no spool, which means that such
+    /// batches are dropped.
+    ///
+    /// Only batches which failed with an error that might go away (e.g. a connection error or a
+    /// `503 Service Unavailable`) are stored, after all retries of the [`RetryPolicy`] failed. Each
+    /// batch is written to a separate segment file protected by a checksum. A background thread
+    /// sends the stored batches again (oldest first) every few seconds until Loki is reachable
+    /// again. Since the segments are kept on disk, they are sent even after the application was
+    /// restarted. Segments which are corrupted or rejected by Loki are dropped. A segment is only
+    /// removed once its batch was delivered, which requires a backend reporting the result of
+    /// [`FenrirBackend::send`] (or the built-in `Reqwest` backend).
+    ///
+    /// If the spool would grow beyond `max_bytes`, its oldest segments are dropped (which is
+    /// reported using [`FenrirError::SpoolFull`] and counted by [`Fenrir::dropped_entries`]). The
+    /// directory must not be used by more than one [`Fenrir`] instance at the same time.
+    ///
+    /// # Panics
+    /// This method will panic if the supplied maximum size is 0. Building the [`Fenrir`] instance
+    /// will panic if the directory cannot be created or read.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .spool_dir("/var/spool/my-app/logs", 256 * 1024 * 1024);
+    /// ```
+    pub fn spool_dir<P: Into<PathBuf>>(mut self, path: P, max_bytes: usize) -> FenrirBuilder {
+        if max_bytes == 0 {
+            panic!("Max spool size must be greater than 0");
+        }
+        self.spool = Some((path.into(), max_bytes));
+        self
+    }
+
+    /// Configure the maximum time a single request to Loki may take (including connecting to the
+    /// endpoint and sending the log messages). Defaults to 10 seconds.
+    ///
+    /// # Panics
+    /// This method will panic if the supplied timeout is zero.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .request_timeout(Duration::from_secs(30));
+    /// ```
+    pub fn request_timeout(mut self, timeout: Duration) -> FenrirBuilder {
+        if timeout.is_zero() {
+            panic!("Request timeout must be greater than 0");
+        }
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Configure the maximum number of idle connections to Loki which are kept open, so
+    /// subsequent flushes do not have to establish a new (TLS) connection. Defaults to 3.
+    ///
+    /// Setting the value to 0 disables the reuse of connections.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .connection_pool_size(8);
+    /// ```
+    pub fn connection_pool_size(mut self, size: usize) -> FenrirBuilder {
+        self.connection_pool_size = size;
+        self
+    }
+
+    /// Configure the time after which an idle connection to Loki is closed instead of being
+    /// reused. Defaults to 15 seconds.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .idle_connection_timeout(Duration::from_secs(60));
+    /// ```
+    pub fn idle_connection_timeout(mut self, timeout: Duration) -> FenrirBuilder {
+        self.idle_connection_timeout = timeout;
+        self
+    }
+
+    /// Configure if and when failed requests to Loki are retried by the network backends. By
+    /// default, a request is attempted up to 4 times with an exponentially growing delay (see
+    /// [`RetryPolicy`]).
+    ///
+    /// Be aware that the `Ureq` backend waits for the retries on the thread which flushes the log
+    /// messages.
+    ///
+    /// # Panics
+    /// This method will panic if the `max_attempts` of the supplied policy are 0.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use fenrir_rs::{Fenrir, RetryPolicy};
+    ///
+    /// let builder = Fenrir::builder()
+    ///    .retry_policy(RetryPolicy {
+    ///        max_attempts: 6,
+    ///        max_delay: Duration::from_secs(10),
+    ///        ..RetryPolicy::default()
+    ///    });
+    /// ```
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> FenrirBuilder {
+        if policy.max_attempts == 0 {
+            panic!("The retry policy must allow at least one attempt");
+        }
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Set a callback which is called every time log messages could not be delivered to Loki.
+    ///
+    /// The callback receives the error which occurred as well as information about the dropped
+    /// log messages. It can be used for counting, printing or alerting on failed deliveries. Be
+    /// aware that log messages logged from within the callback might be sent to Loki again.
+    ///
+    /// The callback is also called for warnings which did not cause log messages to be dropped
+    /// (like [`FenrirError::LabelRejected`]), in which case the number of dropped entries is 0.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .on_error(|error, dropped| {
+    ///         eprintln!("Dropped {} log entries: {}", dropped.entries, error);
+    ///     });
+    /// ```
+    pub fn on_error<F>(mut self, callback: F) -> FenrirBuilder
+    where
+        F: Fn(&FenrirError, DroppedBatchInfo) + Send + Sync + 'static,
+    {
+        self.error_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Panic every time log messages could not be delivered to Loki (after calling the callback
+    /// set with [`FenrirBuilder::on_error`]).
+    ///
+    /// This is mostly useful during development or in tests. By default, undeliverable log
+    /// messages are dropped without panicking.
+    ///
+    /// The panic happens on the thread which flushed the log messages. If this was the worker
+    /// thread (see [`FenrirBuilder::background_dispatch`] and [`FenrirBuilder::flush_interval`]),
+    /// only the worker is stopped and all further log messages are flushed by the logging threads
+    /// instead (which then panic if they cannot be delivered either).
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::Fenrir;
+    ///
+    /// let builder = Fenrir::builder()
+    ///     .panic_on_error();
+    /// ```
+    pub fn panic_on_error(mut self) -> FenrirBuilder {
+        self.panic_on_error = true;
+        self
+    }
+
+    /// Create a new `Fenrir` instance with the parameters supplied to this struct before calling this method.
+    ///
+    /// Before creating a new instance, the supplied parameters are validated (in contrast to [`FenrirBuilder::build`]
+    /// which does not validate the supplied parameters).
+    ///
+    /// # Panics
+    /// The method will panic if one or more of the supplied parameters are not valid or seem to have
+    /// unintended values.
+    ///
+    /// # Example
+    /// ```should_panic
+    /// use url::Url;
+    /// use fenrir_rs::{Fenrir, NetworkingBackend, SerializationFormat};
+    ///
+    /// let fenrir = Fenrir::builder()
+    ///     .endpoint(Url::parse("https://loki.example.com").unwrap())
+    ///     .network(NetworkingBackend::None)
+    ///     .format(SerializationFormat::None)
+    ///     .build_with_validation();
+    /// ```
+    pub fn build_with_validation(self) -> Fenrir {
+        // panic if no network backend was selected
+        if self.network_backend == NetworkingBackend::None && self.custom_backend.is_none() {
+            panic!(
+                "You have to select a `NetworkingBackend` before creating an instance of `Fenrir`"
+            );
+        }
+
+        // panic if no serialization format was selected
+        if self.serialization_format == SerializationFormat::None {
+            panic!("You have to select a `SerializationFormat` before creating an instance of `Fenrir`");
+        }
+
+        // panic if an async credential provider should be used with a sync network backend
+        if matches!(self.credential_provider, Some(Provider::Async(_)))
+            && !self.network_backend.is_async()
+        {
+            panic!("You have to select an async network backend if you want to use an async credential provider");
+        }
+
+        // panic if no runtime was set and the selected network backend is async
+        if self.runtime.is_none() && self.network_backend.is_async() {
+            panic!("You have to set a runtime handle before creating an instance of `Fenrir` if you want to use an async network backend");
+        }
+
+        // after the validation, we can call the actual build method to create the new Fenrir instance
+        self.build()
+    }
+
+    /// Create a new `Fenrir` instance with the parameters supplied to this struct before calling this method.
+    ///
+    /// # Note
+    /// If an async network backend is selected, this method will panic if no runtime handle was set,
+    /// and this method is called outside the context of a Tokio 1.x runtime. It will also panic if
+    /// the push path cannot be appended to the endpoint (e.g. for `mailto:` URLs).
+    ///
+    /// # Example
+    /// ```
+    /// use url::Url;
+    /// use fenrir_rs::{Fenrir, NetworkingBackend, SerializationFormat};
+    ///
+    /// let fenrir = Fenrir::builder()
+    ///     .endpoint(Url::parse("https://loki.example.com").unwrap())
+    ///     .network(NetworkingBackend::Ureq)
+    ///     .format(SerializationFormat::Json)
+    ///     .build();
+    /// ```
+    pub fn build(self) -> Fenrir {
+        use crate::noop::NoopBackend;
+
+        // panic if the number of logs to buffer is 0 (will cause infinite memory growth otherwise)
+        if self.flush_threshold == 0 {
+            panic!("You have to set a buffer size greater than 0");
+        }
+
+        // the async network backends and the background worker need a handle to the tokio runtime
+        #[cfg(feature = "async-tokio")]
+        let runtime_handle = match self.network_backend.is_async() && self.custom_backend.is_none()
+        {
+            true => Some(self.runtime.unwrap_or_else(tokio::runtime::Handle::current)),
+            false => None,
+        };
+
+        // the handler for errors is shared between the backend and the shipper
+        let error_handler = Arc::new(ErrorHandler {
+            callback: self.error_callback,
+            panic_on_error: self.panic_on_error,
+        });
+        for warning in &self.configuration_warnings {
+            error_handler.warn(warning);
+        }
+
+        // the spool has to be opened before the backend, since the async backends use it as well
+        let spool = self.spool.map(|(dir, max_bytes)| {
+            let spool = Spool::open(dir, max_bytes, error_handler.clone())
+                .expect("The spool directory cannot be used");
+            Arc::new(spool)
+        });
+
+        // the URL of the push API is the same for all requests
+        #[cfg_attr(
+            not(any(feature = "ureq", feature = "reqwest-async")),
+            allow(unused_variables) // only used by the network backends
+        )]
+        let push_url = push_url(&self.endpoint, &self.push_path)
+            .expect("The push path cannot be appended to the endpoint");
+
+        // the credentials of a provider are cached until they expire
+        #[cfg_attr(
+            not(any(feature = "ureq", feature = "reqwest-async")),
+            allow(unused_variables) // only used by the network backends
+        )]
+        let credential_cache = self
+            .credential_provider
+            .map(|provider| Arc::new(CredentialCache::new(provider)));
+
+        // determine the serialization function to use
+        let serializer = match self.serialization_format {
+            SerializationFormat::None => noop_serializer,
+
+            #[cfg(feature = "json")]
+            SerializationFormat::Json => |data: &Streams| -> Result<Vec<u8>, FenrirError> {
+                serde_json::to_vec(data)
+                    .map_err(|error| FenrirError::Serialization(Box::new(error)))
+            },
+
+            #[cfg(feature = "protobuf")]
+            SerializationFormat::Protobuf => crate::protobuf::serialize,
+        };
+        let encoder = Encoder {
+            serializer,
+            format: self.serialization_format,
+            compression: self.compression,
+        };
+
+        // create the instance of the required network backend (a custom backend always takes
+        // precedence over the selected one)
+        let network_backend: Box<dyn FenrirBackend> = if let Some(backend) = self.custom_backend {
+            backend
+        } else {
+            match self.network_backend {
+                NetworkingBackend::None => Box::new(NoopBackend {}),
+
+                #[cfg(feature = "ureq")]
+                NetworkingBackend::Ureq => Box::new(crate::ureq::UreqBackend {
+                    authentication: self.authentication,
+                    credentials: self.credentials,
+                    push_url: push_url.clone(),
+                    agent: crate::ureq::new_agent(
+                        self.request_timeout,
+                        self.connection_pool_size,
+                        self.idle_connection_timeout,
+                    ),
+                    retry_policy: self.retry_policy,
+                    headers: self.headers,
+                    credential_cache,
+                }),
+
+                #[cfg(feature = "reqwest-async")]
+                NetworkingBackend::Reqwest => Box::new(crate::reqwest::ReqwestBackend {
+                    authentication: self.authentication,
+                    credentials: self.credentials,
+                    push_url: push_url.clone(),
+                    client: ::reqwest::Client::builder()
+                        .timeout(self.request_timeout)
+                        .pool_max_idle_per_host(self.connection_pool_size)
+                        .pool_idle_timeout(self.idle_connection_timeout)
+                        .build()
+                        .expect("Could not create the HTTP client for sending the logs"),
+                    runtime_handle: runtime_handle.clone().unwrap(),
+                    in_flight: Default::default(),
+                    error_handler: error_handler.clone(),
+                    retry_policy: self.retry_policy,
+                    headers: self.headers,
+                    credential_cache,
+                    spool: spool.clone(),
+                    encoder,
+                }),
+            }
+        };
+
+        // the targets excluded by the backend can be adjusted by the user
+        let mut excluded_targets = network_backend
+            .excluded_targets()
+            .iter()
+            .map(|target| target.to_string())
+            .filter(|target| !self.allowed_targets.contains(target))
+            .collect::<Vec<_>>();
+        excluded_targets.extend(self.excluded_targets);
+
+        let shipper = Arc::new(LogShipper {
+            backend: network_backend,
+            encoder,
+            log_stream: Mutex::new(LogBuffer::default()),
+            limits: self.buffer_limits,
+            max_batch_bytes: self.max_batch_bytes,
+            space_available: Condvar::new(),
+            undelivered: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            background_dispatch: self.background_dispatch,
+            worker_signal: Arc::new(WorkerSignal::default()),
+            spool,
+            error_handler,
+        });
+
+        // start the background worker for flushing the logs periodically (if requested)
+        let is_blocking = matches!(self.buffer_limits.policy, OverflowPolicy::Block(_));
+        let flush_worker = match self.background_dispatch || is_blocking {
+            // only a worker thread can be asked to flush the buffer
+            true => Some(FlushWorker::spawn_thread(
+                shipper.clone(),
+                self.flush_interval,
+            )),
+            false => self.flush_interval.map(|interval| {
+                #[cfg(feature = "async-tokio")]
+                if let Some(runtime_handle) = runtime_handle {
+                    return FlushWorker::spawn_task(shipper.clone(), interval, &runtime_handle);
+                }
+                FlushWorker::spawn_thread(shipper.clone(), Some(interval))
+            }),
+        };
+
+        // the spooled batches are replayed in the background
+        let replay_worker = shipper
+            .spool
+            .as_ref()
+            .map(|_| FlushWorker::spawn_replay_thread(shipper.clone()));
+
+        // create and return the actual backend
+        Fenrir {
+            shipper,
+            filter: self.filter,
+            excluded_targets,
+            include_level: self.include_level,
+            include_framework: self.include_framework,
+            additional_tags: self.additional_tags,
+            flush_threshold: self.flush_threshold,
+            max_message_size: self.max_message_size,
+            line_format: self.line_format,
+            tenant_id: self.tenant_id,
+            #[cfg(feature = "structured_logging")]
+            tenant_key: self.tenant_key,
+            #[cfg(feature = "structured_logging")]
+            metadata_keys: self.metadata_keys,
+            #[cfg(feature = "structured_logging")]
+            label_guard: self.label_policy.map(LabelGuard::new),
+            #[cfg(feature = "structured_logging")]
+            reported_collisions: Mutex::new(std::collections::HashSet::new()),
+            flush_interval: self.flush_interval,
+            flush_worker: Mutex::new(flush_worker),
+            replay_worker: Mutex::new(replay_worker),
+        }
+    }
+}
+
+/// A serialization implementation which does nothing when requesting to serialize a object
+pub(crate) fn noop_serializer(_: &Streams) -> Result<Vec<u8>, FenrirError> {
+    Ok(vec![])
+}
+
+/// Decides which structured logging values are attached as structured metadata instead of labels
+#[cfg(feature = "structured_logging")]
+enum MetadataKeys {
+    /// Only the values with one of the keys are attached as structured metadata
+    Selected(Vec<String>),
+    /// All values except for the ones with one of the keys are attached as structured metadata
+    AllExcept(Vec<String>),
+}
+
+#[cfg(feature = "structured_logging")]
+impl MetadataKeys {
+    /// Check if the value with the supplied `key` is attached as structured metadata
+    fn contains(&self, key: &str) -> bool {
+        match self {
+            MetadataKeys::Selected(keys) => keys.iter().any(|selected| selected == key),
+            MetadataKeys::AllExcept(keys) => !keys.iter().any(|excluded| excluded == key),
+        }
+    }
+}
+
+/// A struct for visiting all structured logging labels of a log message and collecting them
+#[cfg(feature = "structured_logging")]
+struct LokiVisitor<'kvs> {
+    values: HashMap<log::kv::Key<'kvs>, log::kv::Value<'kvs>>,
+}
+
+/// The implementation for the visitor pattern for collecting all labels attached to a log
+/// message
+#[cfg(feature = "structured_logging")]
+impl<'kvs> LokiVisitor<'kvs> {
+    /// Create a new visitor with an initial capacity of `count` labels.
+    pub fn new(count: usize) -> Self {
+        Self {
+            values: HashMap::with_capacity(count),
+        }
+    }
+
+    /// Read the key-value-pair attached to a log message and collect them
+    pub fn read_kv(
+        &'kvs mut self,
+        source: &'kvs dyn Source,
+    ) -> Result<&'kvs HashMap<log::kv::Key<'kvs>, log::kv::Value<'kvs>>, log::kv::Error> {
+        for _ in 0..source.count() {
+            source.visit(self)?;
+        }
+        Ok(&self.values)
+    }
+}
+
+/// The [`Visitor'] implementation for the [`LokiVisitor`]
+#[cfg(feature = "structured_logging")]
+impl<'kvs> Visitor<'kvs> for LokiVisitor<'kvs> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.values.insert(key, value);
+        Ok(())
+    }
+}
+
+/// The buffer which collects all log entries until they are flushed to Loki.
+///
+/// Entries with an identical label set are grouped into a single [`Stream`], so each distinct
+/// label combination is only sent once per flush.
+#[derive(Default)]
+pub(crate) struct LogBuffer {
+    /// The streams collected since the last flush
+    streams: Vec<Stream>,
+    /// A lookup table from a tenant and label set to the index of its stream in
+    /// [`LogBuffer::streams`]
+    index: HashMap<(Option<String>, BTreeMap<String, String>), usize>,
+    /// The index of the stream of each buffered log entry (in the order they were added), so the
+    /// oldest entry can be found without searching all streams
+    order: VecDeque<usize>,
+    /// The number of log entries in all buffered streams
+    entries: usize,
+    /// The size of the log lines in all buffered streams in bytes
+    bytes: usize,
+    /// The approximate size of all buffered streams once they are serialized in bytes
+    size: usize,
+    /// The point in time at which the oldest buffered log entry was added. Evicting entries does
+    /// not update it (unless the buffer gets empty), so it is an upper bound for the age of the
+    /// oldest entry which is still buffered.
+    oldest_entry: Option<Instant>,
+}
+
+impl LogBuffer {
+    /// Add a log entry to the stream with the supplied `tenant` and `labels` and return the number
+    /// of buffered log entries afterward.
+    pub(crate) fn push(
+        &mut self,
+        tenant: Option<String>,
+        labels: BTreeMap<String, String>,
+        entry: LogEntry,
+    ) -> usize {
+        self.bytes += entry.line.len();
+        self.size += entry.approximate_size();
+        let key = (tenant, labels);
+        match self.index.get(&key) {
+            Some(&index) => {
+                // the labels of streams emptied by dropping their oldest entries are sent again
+                let stream = &mut self.streams[index];
+                if stream.values.is_empty() {
+                    self.size += stream.labels_size();
+                }
+                stream.values.push_back(entry);
+                self.order.push_back(index);
+            }
+            None => {
+                self.index.insert(key.clone(), self.streams.len());
+                self.order.push_back(self.streams.len());
+                let (tenant, labels) = key;
+                let stream = Stream {
+                    stream: labels,
+                    values: VecDeque::from([entry]),
+                    tenant,
+                };
+                self.size += stream.labels_size();
+                self.streams.push(stream);
+            }
+        }
+        self.oldest_entry.get_or_insert_with(Instant::now);
+        self.entries += 1;
+        self.entries
+    }
+
+    /// Check if the buffer does not contain any log entries
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Remove the oldest log entry from the buffer and return the size of its log line or `None`
+    /// if the buffer is empty
+    pub(crate) fn pop_oldest(&mut self) -> Option<usize> {
+        // the entries of each stream are ordered, so the oldest entry is the first one of its stream
+        let index = self.order.pop_front()?;
+        let stream = &mut self.streams[index];
+        let entry = stream.values.pop_front()?;
+        // the labels of an empty stream are not serialized at all
+        if stream.values.is_empty() {
+            self.size -= stream.labels_size();
+        }
+        self.entries -= 1;
+        self.bytes -= entry.line.len();
+        self.size -= entry.approximate_size();
+        if self.entries == 0 {
+            self.oldest_entry = None;
+        }
+        Some(entry.line.len())
+    }
+
+    /// Remove all buffered streams from the buffer and return them together with the number of
+    /// log entries they contain
+    pub(crate) fn take(&mut self) -> (Vec<Stream>, usize) {
+        self.index.clear();
+        self.order.clear();
+        self.oldest_entry = None;
+        self.bytes = 0;
+        self.size = 0;
+
+        // streams might have been emptied by dropping their oldest entries
+        let mut streams = std::mem::take(&mut self.streams);
+        streams.retain(|stream| !stream.values.is_empty());
+        (streams, std::mem::take(&mut self.entries))
+    }
+}
+
+/// The data structure used for attaching tags / labels to logging entries before sending them
+/// to Loki
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Stream {
+    /// The tags which should be attached to the logging entries
+    pub(crate) stream: BTreeMap<String, String>,
+    /// The actual log messages to store with the corresponding meta information
+    pub(crate) values: VecDeque<LogEntry>,
+    /// The tenant the stream belongs to (sent as a header instead of being serialized)
+    #[serde(skip)]
+    pub(crate) tenant: Option<String>,
+}
+
+impl Stream {
+    /// Get the approximate size of the labels of the stream once it is serialized in bytes
+    pub(crate) fn labels_size(&self) -> usize {
+        let labels = self
+            .stream
+            .iter()
+            .map(|(key, value)| key.len() + value.len() + LABEL_OVERHEAD)
+            .sum::<usize>();
+        STREAM_OVERHEAD + labels
+    }
+
+    /// Get the approximate size of the stream (including all of its entries) once it is
+    /// serialized in bytes
+    pub(crate) fn approximate_size(&self) -> usize {
+        let entries = self
+            .values
+            .iter()
+            .map(LogEntry::approximate_size)
+            .sum::<usize>();
+        self.labels_size() + entries
+    }
+
+    /// Create a stream with the same labels and tenant, but with different log entries
+    fn with_values(&self, values: VecDeque<LogEntry>) -> Stream {
+        Stream {
+            stream: self.stream.clone(),
+            values,
+            tenant: self.tenant.clone(),
+        }
+    }
+}
+
+/// A single log message together with the time it was logged at
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LogEntry {
+    /// The time the log message was logged at (relative to the UNIX epoch)
+    pub(crate) timestamp: Duration,
+    /// The serialized log message
+    pub(crate) line: String,
+    /// The structured metadata attached to the log message (which is not indexed by Loki)
+    pub(crate) metadata: BTreeMap<String, String>,
+}
+
+impl LogEntry {
+    /// Get the approximate size of the log entry once it is serialized in bytes
+    pub(crate) fn approximate_size(&self) -> usize {
+        let metadata = self
+            .metadata
+            .iter()
+            .map(|(key, value)| key.len() + value.len() + LABEL_OVERHEAD)
+            .sum::<usize>();
+        self.line.len() + metadata + ENTRY_OVERHEAD
+    }
+}
+
+/// Loki expects each entry as an array of the timestamp (in nanoseconds, encoded as string) and
+/// the log line, followed by an object with the structured metadata (if there is any)
+impl Serialize for LogEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let length = match self.metadata.is_empty() {
+            true => 2,
+            false => 3,
+        };
+        let mut tuple = serializer.serialize_tuple(length)?;
+        tuple.serialize_element(&self.timestamp.as_nanos().to_string())?;
+        tuple.serialize_element(&self.line)?;
+        if !self.metadata.is_empty() {
+            tuple.serialize_element(&self.metadata)?;
+        }
+        tuple.end()
+    }
+}
+
+/// The data structure used for encoding a single log message before sending it to Loki
+#[derive(Serialize)]
+#[cfg(feature = "json")]
+pub(crate) struct SerializedEvent<'a> {
+    /// The file name of the log message source
+    pub(crate) file: Option<&'a str>,
+    /// The line number of the log message source
+    pub(crate) line: Option<u32>,
+    /// The module name of the log message source
+    pub(crate) module: Option<&'a str>,
+    /// The log level of the log message
+    pub(crate) level: &'static str,
+    /// The target of the log message
+    pub(crate) target: &'a str,
+    /// The actual log message
+    pub(crate) message: String,
+}
+
+/// The base data structure Loki expects when receiving logging messages.
+#[derive(Serialize)]
+pub(crate) struct Streams<'a> {
+    /// A list of all logging messages with the attached meta information which should be logged
+    /// by Loki
+    pub(crate) streams: &'a [Stream],
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::{
+        is_valid_label_name, push_url, sanitize_label_name, split_by_size, split_by_tenant,
+        truncate_label_value, AsyncCredentialProvider, AuthenticationMethod, Batch, BufferLimits,
+        Credential, CredentialFuture, DroppedBatchInfo, Fenrir, FenrirBackend, FenrirError,
+        LogBuffer, LogEntry, NetworkingBackend, OverflowPolicy, SerializationFormat, Stream,
+    };
+    use log::{Log, Record};
+    use parking_lot::RwLock;
+    use std::any::TypeId;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use url::Url;
+
+    /// A backend which just counts how often it was asked to send something
+    pub(crate) struct CountingBackend(pub(crate) Arc<AtomicUsize>);
+
+    impl FenrirBackend for CountingBackend {
+        fn send(&self, _: Batch) -> Result<(), FenrirError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// A backend which refuses to send anything
+    struct FailingBackend;
+
+    impl FenrirBackend for FailingBackend {
+        fn send(&self, _: Batch) -> Result<(), FenrirError> {
+            Err(FenrirError::HttpStatus {
+                status: 503,
+                body: String::new(),
+                retry_after: None,
+            })
+        }
+    }
+
+    /// A backend which records all batches it was asked to send
+    #[derive(Clone, Default)]
+    struct RecordingBackend(Arc<RwLock<Vec<Batch>>>);
+
+    impl RecordingBackend {
+        /// Get the tenant and the number of entries of each recorded batch
+        fn tenants(&self) -> Vec<(Option<String>, usize)> {
+            self.0
+                .read()
+                .iter()
+                .map(|batch| (batch.tenant().map(str::to_string), batch.entries()))
+                .collect()
+        }
+    }
+
+    impl FenrirBackend for RecordingBackend {
+        fn send(&self, batch: Batch) -> Result<(), FenrirError> {
+            self.0.write().push(batch);
+            Ok(())
+        }
+    }
+
+    /// A backend which rejects batches with more than the supplied number of entries as too large
+    /// and records all other batches
+    struct SizeLimitedBackend(usize, RecordingBackend);
+
+    impl FenrirBackend for SizeLimitedBackend {
+        fn send(&self, batch: Batch) -> Result<(), FenrirError> {
+            if batch.entries() > self.0 {
+                return Err(FenrirError::HttpStatus {
+                    status: 413,
+                    body: String::new(),
+                    retry_after: None,
+                });
+            }
+            self.1.send(batch)
+        }
+    }
+
+    /// Wait until the `condition` is met (checking it periodically) and return `false` if it was
+    /// not met within a few seconds
+    pub(crate) fn wait_until(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    /// Create a new [`Fenrir`] instance which uses a [`CountingBackend`]
+    fn counting_fenrir() -> (Fenrir, Arc<AtomicUsize>) {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(CountingBackend(counter.clone())))
+            .build();
+        (fenrir, counter)
+    }
+
+    #[test]
+    #[should_panic]
+    fn building_a_validated_fenrir_instance_without_network_backend_panics() {
+        let _fenrir = Fenrir::builder()
+            .format(SerializationFormat::Json)
+            .build_with_validation();
+    }
+
+    #[test]
+    fn building_a_non_validated_fenrir_instance_without_network_backend_does_not_panic() {
+        let _fenrir = Fenrir::builder().format(SerializationFormat::Json).build();
+    }
+
+    #[test]
+    #[should_panic]
+    fn building_a_validated_fenrir_instance_without_serialization_backend_panics() {
+        let _fenrir = Fenrir::builder()
+            .network(NetworkingBackend::Ureq)
+            .build_with_validation();
+    }
+
+    #[test]
+    fn building_a_non_validated_fenrir_instance_without_serialization_backend_does_not_panic() {
+        let _fenrir = Fenrir::builder().network(NetworkingBackend::Ureq).build();
+    }
+
+    #[test]
+    fn building_a_validated_fenrir_instance_with_a_custom_backend_does_not_panic() {
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(FailingBackend))
+            .format(SerializationFormat::Json)
+            .build_with_validation();
+        assert_eq!(
+            fenrir.shipper.backend.authentication_method(),
+            AuthenticationMethod::None
+        );
+        assert_eq!(fenrir.shipper.backend.credentials(), None);
+        assert_eq!(
+            fenrir.shipper.backend.internal_type(),
+            TypeId::of::<FailingBackend>()
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn using_an_async_credential_provider_with_a_sync_backend_panics() {
+        struct TokenEndpoint;
+
+        impl AsyncCredentialProvider for TokenEndpoint {
+            fn credential(&self) -> CredentialFuture<'_> {
+                Box::pin(async { Ok(Credential::bearer("token")) })
+            }
+        }
+
+        let _ = Fenrir::builder()
+            .network(NetworkingBackend::Ureq)
+            .format(SerializationFormat::Json)
+            .async_credential_provider(TokenEndpoint)
+            .build_with_validation();
+    }
+
+    #[test]
+    fn the_push_path_is_appended_to_the_path_of_the_endpoint() {
+        let push_url = |endpoint: &str, push_path: &str| {
+            push_url(&Url::parse(endpoint).unwrap(), push_path)
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            push_url("https://loki.example.com", "loki/api/v1/push"),
+            "https://loki.example.com/loki/api/v1/push"
+        );
+        assert_eq!(
+            push_url("https://gateway.example.com/loki-eu/", "loki/api/v1/push"),
+            "https://gateway.example.com/loki-eu/loki/api/v1/push"
+        );
+        assert_eq!(
+            push_url("https://gateway.example.com/loki-eu", "/loki/api/v1/push"),
+            "https://gateway.example.com/loki-eu/loki/api/v1/push"
+        );
+        assert_eq!(
+            push_url("http://vector:8080", "ingest"),
+            "http://vector:8080/ingest"
+        );
+    }
+
+    #[test]
+    fn the_authorization_header_matches_the_authentication_method() {
+        assert_eq!(AuthenticationMethod::None.authorization("abc"), None);
+        assert_eq!(
+            AuthenticationMethod::Basic.authorization("abc"),
+            Some("Basic abc".to_string())
+        );
+        assert_eq!(
+            AuthenticationMethod::Bearer("token".to_string()).authorization(""),
+            Some("Bearer token".to_string())
+        );
+    }
+
+    #[test]
+    fn the_debug_output_of_the_authentication_method_does_not_contain_the_token() {
+        assert_eq!(
+            format!("{:?}", AuthenticationMethod::Bearer("secret".to_string())),
+            "Bearer(<redacted>)"
+        );
+        assert_eq!(format!("{:?}", AuthenticationMethod::Basic), "Basic");
+    }
+
+    #[test]
+    fn adding_a_header_again_replaces_its_value() {
+        let builder = Fenrir::builder()
+            .header("X-Gateway-Key", "first")
+            .header("X-Proxy", "proxy")
+            .header("x-gateway-key", "second");
+        assert_eq!(
+            builder.headers,
+            vec![
+                ("X-Proxy".to_string(), "proxy".to_string()),
+                ("x-gateway-key".to_string(), "second".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn adding_a_header_with_an_invalid_name_panics() {
+        let _ = Fenrir::builder().header("X Gateway", "value");
+    }
+
+    #[test]
+    #[should_panic]
+    fn adding_a_header_with_an_invalid_value_panics() {
+        let _ = Fenrir::builder().header("X-Gateway", "first\r\nX-Injected: second");
+    }
+
+    #[test]
+    fn entries_with_identical_labels_are_grouped_into_a_single_stream() {
+        let labels = |level: &str| {
+            BTreeMap::from([
+                ("service".to_string(), "test".to_string()),
+                ("level".to_string(), level.to_string()),
+            ])
+        };
+        let entry = |message: &str| LogEntry {
+            timestamp: Duration::ZERO,
+            line: message.to_string(),
+            metadata: BTreeMap::new(),
+        };
+
+        let mut buffer = LogBuffer::default();
+        assert_eq!(buffer.push(None, labels("INFO"), entry("first")), 1);
+        assert_eq!(buffer.push(None, labels("WARN"), entry("second")), 2);
+        assert_eq!(buffer.push(None, labels("INFO"), entry("third")), 3);
+
+        let (streams, entries) = buffer.take();
+        assert_eq!(entries, 3);
+        assert!(buffer.take().0.is_empty());
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].stream, labels("INFO"));
+        assert_eq!(streams[0].values, vec![entry("first"), entry("third")]);
+        assert_eq!(streams[1].stream, labels("WARN"));
+        assert_eq!(streams[1].values, vec![entry("second")]);
+    }
+
+    #[test]
+    fn log_entries_are_sent_to_the_configured_tenant() {
+        let backend = RecordingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(backend.clone()))
+            .tenant_id("team-a")
+            .build();
+        fenrir.log(&Record::builder().args(format_args!("first")).build());
+        fenrir.log(&Record::builder().args(format_args!("second")).build());
+        fenrir.flush();
+
+        assert_eq!(backend.tenants(), vec![(Some("team-a".to_string()), 2)]);
+    }
+
+    #[test]
+    fn streams_of_different_tenants_are_split_into_separate_groups() {
+        let entry = LogEntry {
+            timestamp: Duration::ZERO,
+            line: "message".to_string(),
+            metadata: BTreeMap::new(),
+        };
+        let labels = |level: &str| BTreeMap::from([("level".to_string(), level.to_string())]);
+
+        let mut buffer = LogBuffer::default();
+        buffer.push(Some("a".to_string()), labels("INFO"), entry.clone());
+        buffer.push(None, labels("INFO"), entry.clone());
+        buffer.push(Some("b".to_string()), labels("INFO"), entry.clone());
+        buffer.push(Some("a".to_string()), labels("WARN"), entry.clone());
+        buffer.push(Some("a".to_string()), labels("INFO"), entry);
+
+        let groups = split_by_tenant(buffer.take().0);
+        let tenants = groups
+            .iter()
+            .map(|(tenant, streams)| (tenant.as_deref(), streams.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(tenants, vec![(Some("a"), 2), (None, 1), (Some("b"), 1)]);
+        assert_eq!(groups[0].1[0].values.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn the_tenant_can_be_selected_using_structured_logging() {
+        let backend = RecordingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(backend.clone()))
+            .tenant_id("default")
+            .tenant_key("tenant")
+            .build();
+        let key_values = [("tenant", "team-b"), ("service", "app")];
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("first"))
+                .key_values(&key_values)
+                .build(),
+        );
+        fenrir.log(&Record::builder().args(format_args!("second")).build());
+        fenrir.flush();
+
+        assert_eq!(
+            backend.tenants(),
+            vec![
+                (Some("team-b".to_string()), 1),
+                (Some("default".to_string()), 1)
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn labels_which_violate_the_label_policy_are_moved_into_the_log_line() {
+        use crate::{LabelKeys, LabelPolicy};
+
+        let warnings = Arc::new(RwLock::new(Vec::new()));
+        let callback_warnings = warnings.clone();
+        let fenrir = Fenrir::builder()
+            .label_policy(LabelPolicy::new().with_keys(LabelKeys::deny(&["user_id"])))
+            .on_error(move |error, info| {
+                callback_warnings
+                    .write()
+                    .push((error.to_string(), info.entries));
+            })
+            .build();
+        let key_values = [("service", "app"), ("user_id", "4711")];
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("message"))
+                .key_values(&key_values)
+                .build(),
+        );
+
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert_eq!(
+            streams[0].stream,
+            BTreeMap::from([("service".to_string(), "app".to_string())])
+        );
+        assert!(streams[0].values[0].line.contains("message user_id=4711"));
+        assert_eq!(
+            *warnings.read(),
+            vec![(
+                "The label user_id was rejected since the key is denied".to_string(),
+                0
+            )]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    #[should_panic(expected = "The label policy must allow at least one value per key")]
+    fn a_label_policy_without_values_panics() {
+        let _builder = Fenrir::builder().label_policy(crate::LabelPolicy {
+            max_values_per_key: Some(0),
+            ..crate::LabelPolicy::default()
+        });
+    }
+
+    #[test]
+    fn label_names_are_sanitized_to_the_rules_of_loki() {
+        assert!(is_valid_label_name("service_name"));
+        assert!(is_valid_label_name("_private1"));
+        assert!(!is_valid_label_name("service-name"));
+        assert!(!is_valid_label_name("1st"));
+        assert!(!is_valid_label_name(""));
+        assert!(!is_valid_label_name(&"a".repeat(1025)));
+
+        assert_eq!(sanitize_label_name("http.method"), "http_method");
+        assert_eq!(sanitize_label_name("1st"), "_1st");
+        assert_eq!(sanitize_label_name("größe"), "gr__e");
+        assert_eq!(sanitize_label_name(""), "_");
+        assert_eq!(sanitize_label_name(&"a".repeat(2000)).len(), 1024);
+
+        assert_eq!(truncate_label_value("value".to_string()), "value");
+        let value = truncate_label_value("ä".repeat(1500));
+        assert_eq!(value.len(), 2048);
+        assert!(value.chars().all(|char| char == 'ä'));
+    }
+
+    #[test]
+    #[should_panic(expected = "The tag name \"service-name\" is not a valid label name")]
+    fn tags_with_an_invalid_name_panic() {
+        let _builder = Fenrir::builder().tag("service-name", "app");
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn structured_logging_values_are_attached_with_valid_label_names() {
+        let fenrir = Fenrir::builder().build();
+        let key_values = [("http.method", "GET")];
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("message"))
+                .key_values(&key_values)
+                .build(),
+        );
+
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert_eq!(
+            streams[0].stream,
+            BTreeMap::from([("http_method".to_string(), "GET".to_string())])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn logfmt_lines_contain_the_structured_logging_values_as_fields() {
+        use crate::LineFormat;
+
+        let fenrir = Fenrir::builder()
+            .line_format(LineFormat::Logfmt)
+            .tenant_key("tenant")
+            .build();
+        let key_values = [("user_id", "4711"), ("tenant", "team-a"), ("path", "/a b")];
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("Request failed"))
+                .level(log::Level::Error)
+                .target("app")
+                .key_values(&key_values)
+                .build(),
+        );
+
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert_eq!(
+            streams[0].values[0].line,
+            "level=error target=app msg=\"Request failed\" path=\"/a b\" user_id=4711"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn logfmt_lines_do_not_contain_values_dropped_by_the_label_policy() {
+        use crate::{LabelKeys, LabelPolicy, LineFormat, RejectedLabelAction};
+
+        let fenrir = Fenrir::builder()
+            .line_format(LineFormat::Logfmt)
+            .label_policy(
+                LabelPolicy::new()
+                    .with_keys(LabelKeys::deny(&["user_id"]))
+                    .with_rejected(RejectedLabelAction::Drop),
+            )
+            .build();
+        let key_values = [("user.id", "4711"), ("service", "app")];
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("message"))
+                .target("app")
+                .key_values(&key_values)
+                .build(),
+        );
+
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert_eq!(
+            streams[0].values[0].line,
+            "level=info target=app msg=message service=app"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn logfmt_lines_do_not_contain_values_with_a_repeated_label_name() {
+        use crate::LineFormat;
+
+        let fenrir = Fenrir::builder().line_format(LineFormat::Logfmt).build();
+        let key_values = [("http_method", "POST"), ("http.method", "GET")];
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("message"))
+                .target("app")
+                .key_values(&key_values)
+                .build(),
+        );
+
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert_eq!(
+            streams[0].values[0].line,
+            "level=info target=app msg=message http.method=GET"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn the_json_line_format_can_be_selected_at_runtime() {
+        use crate::LineFormat;
+
+        let fenrir = Fenrir::builder().line_format(LineFormat::Json).build();
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("message"))
+                .target("app")
+                .build(),
+        );
+
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert!(streams[0].values[0]
+            .line
+            .starts_with("{\"file\":null,\"line\":null"));
+        assert!(streams[0].values[0]
+            .line
+            .contains("\"message\":\"message\""));
+    }
+
+    #[test]
+    fn shutting_down_flushes_all_buffered_entries() {
+        let (fenrir, counter) = counting_fenrir();
+        fenrir.log(&Record::builder().args(format_args!("first")).build());
+        fenrir.log(&Record::builder().args(format_args!("second")).build());
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+
+        assert_eq!(fenrir.shutdown(Duration::from_secs(1)), 0);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dropping_an_instance_flushes_all_buffered_entries() {
+        let (fenrir, counter) = counting_fenrir();
+        fenrir.log(&Record::builder().args(format_args!("message")).build());
+        drop(fenrir);
+
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn streams_are_serialized_to_the_json_format_of_loki() {
+        use crate::{Stream, Streams};
+
+        let streams = [Stream {
+            stream: BTreeMap::from([("service".to_string(), "app".to_string())]),
+            values: VecDeque::from([LogEntry {
+                timestamp: Duration::new(1, 5),
+                line: "message".to_string(),
+                metadata: BTreeMap::new(),
+            }]),
+            tenant: Some("team-a".to_string()),
+        }];
+        assert_eq!(
+            serde_json::to_string(&Streams { streams: &streams }).unwrap(),
+            r#"{"streams":[{"stream":{"service":"app"},"values":[["1000000005","message"]]}]}"#
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn structured_metadata_is_serialized_as_the_third_element_of_an_entry() {
+        let entry = LogEntry {
+            timestamp: Duration::new(1, 5),
+            line: "message".to_string(),
+            metadata: BTreeMap::from([("request_id".to_string(), "4711".to_string())]),
+        };
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"["1000000005","message",{"request_id":"4711"}]"#
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn selected_values_are_attached_as_structured_metadata() {
+        let key_values = [("service", "app"), ("request_id", "4711")];
+        let log = |fenrir: &Fenrir| {
+            fenrir.log(
+                &Record::builder()
+                    .args(format_args!("message"))
+                    .key_values(&key_values)
+                    .build(),
+            );
+            let (streams, _) = fenrir.shipper.log_stream.lock().take();
+            let metadata = streams[0].values[0].metadata.clone();
+            (streams[0].stream.clone(), metadata)
+        };
+        let service = BTreeMap::from([("service".to_string(), "app".to_string())]);
+        let request_id = BTreeMap::from([("request_id".to_string(), "4711".to_string())]);
+
+        let fenrir = Fenrir::builder()
+            .structured_metadata_keys(&["request_id"])
+            .build();
+        assert_eq!(log(&fenrir), (service.clone(), request_id.clone()));
+
+        let fenrir = Fenrir::builder().label_keys(&["service"]).build();
+        assert_eq!(log(&fenrir), (service, request_id));
+
+        let fenrir = Fenrir::builder().build();
+        assert_eq!(log(&fenrir).0.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn structured_metadata_keys_are_matched_on_the_sanitized_names() {
+        let fenrir = Fenrir::builder()
+            .structured_metadata_keys(&["http_method"])
+            .build();
+        let key_values = [("http.method", "GET")];
+        fenrir.log(
+            &Record::builder()
+                .args(format_args!("message"))
+                .key_values(&key_values)
+                .build(),
+        );
+
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert!(streams[0].stream.is_empty());
+        assert_eq!(
+            streams[0].values[0].metadata,
+            BTreeMap::from([("http_method".to_string(), "GET".to_string())])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "structured_logging")]
+    fn keys_with_the_same_label_name_are_reported_once() {
+        let warnings = Arc::new(RwLock::new(Vec::new()));
+        let callback_warnings = warnings.clone();
+        let fenrir = Fenrir::builder()
+            .on_error(move |error, info| {
+                callback_warnings
+                    .write()
+                    .push((error.to_string(), info.entries));
+            })
+            .build();
+        let key_values = [("http_method", "POST"), ("http.method", "GET")];
+        for _ in 0..2 {
+            fenrir.log(
+                &Record::builder()
+                    .args(format_args!("message"))
+                    .key_values(&key_values)
+                    .build(),
             );
         }
 
-        // panic if no serialization format was selected
-        if self.serialization_format == SerializationFormat::None {
-            panic!("You have to select a `SerializationFormat` before creating an instance of `Fenrir`");
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        assert_eq!(
+            streams[0].stream,
+            BTreeMap::from([("http_method".to_string(), "GET".to_string())])
+        );
+        assert_eq!(streams[0].values.len(), 2);
+        assert_eq!(
+            *warnings.read(),
+            vec![(
+                "The label http_method was rejected since another key has the same label name"
+                    .to_string(),
+                0
+            )]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn gzip_compressed_payloads_can_be_decompressed_again() {
+        use crate::Compression;
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let payload = b"{\"streams\":[]}".repeat(10);
+        let compressed = Compression::Gzip.compress(payload.clone()).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(Compression::Gzip.content_encoding(), Some("gzip"));
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
+    #[test]
+    fn the_error_callback_is_called_for_undeliverable_entries() {
+        let dropped = Arc::new(RwLock::new(Vec::new()));
+        let callback_dropped = dropped.clone();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(FailingBackend))
+            .on_error(move |error, info| {
+                callback_dropped.write().push((error.status(), info));
+            })
+            .build();
+        fenrir.log(&Record::builder().args(format_args!("first")).build());
+        fenrir.log(&Record::builder().args(format_args!("second")).build());
+
+        assert_eq!(fenrir.shutdown(Duration::from_secs(1)), 2);
+        assert_eq!(
+            *dropped.read(),
+            vec![(
+                Some(503),
+                DroppedBatchInfo {
+                    entries: 2,
+                    bytes: 0
+                }
+            )]
+        );
+    }
+
+    /// A backend which records the names of the threads it was called on
+    #[derive(Clone, Default)]
+    struct ThreadRecordingBackend(Arc<RwLock<Vec<Option<String>>>>);
+
+    impl FenrirBackend for ThreadRecordingBackend {
+        fn send(&self, _: Batch) -> Result<(), FenrirError> {
+            let thread = std::thread::current().name().map(str::to_string);
+            self.0.write().push(thread);
+            Ok(())
         }
+    }
 
-        // panic if no runtime was set and the selected network backend is async
-        if self.runtime.is_none() && self.network_backend.is_async() {
-            panic!("You have to set a runtime handle before creating an instance of `Fenrir` if you want to use an async network backend");
+    #[test]
+    fn the_buffer_is_flushed_by_the_worker_thread_with_background_dispatch() {
+        let backend = ThreadRecordingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(backend.clone()))
+            .flush_threshold(2)
+            .background_dispatch()
+            .build();
+        fenrir.log(&Record::builder().args(format_args!("first")).build());
+        fenrir.log(&Record::builder().args(format_args!("second")).build());
+
+        assert!(wait_until(|| !backend.0.read().is_empty()));
+        assert_eq!(*backend.0.read(), vec![Some("fenrir-flush".to_string())]);
+
+        // after shutting down, the logging thread has to flush the buffer on its own
+        fenrir.shutdown(Duration::from_secs(1));
+        fenrir.log(&Record::builder().args(format_args!("third")).build());
+        fenrir.log(&Record::builder().args(format_args!("fourth")).build());
+        assert_eq!(backend.0.read().len(), 2);
+        assert_ne!(backend.0.read()[1], Some("fenrir-flush".to_string()));
+    }
+
+    /// Get the log lines buffered by the supplied [`Fenrir`] instance
+    fn buffered_lines(fenrir: &Fenrir) -> Vec<String> {
+        let (streams, _) = fenrir.shipper.log_stream.lock().take();
+        streams
+            .into_iter()
+            .flat_map(|stream| stream.values)
+            .map(|entry| entry.line)
+            .collect()
+    }
+
+    #[test]
+    fn new_entries_are_dropped_if_the_buffer_is_full() {
+        let dropped = Arc::new(RwLock::new(Vec::new()));
+        let callback_dropped = dropped.clone();
+        let fenrir = Fenrir::builder()
+            .max_buffered_entries(2)
+            .on_error(move |error, info| {
+                callback_dropped
+                    .write()
+                    .push((error.to_string(), info.entries));
+            })
+            .build();
+        for message in ["first", "second", "third"] {
+            fenrir.log(&Record::builder().args(format_args!("{}", message)).build());
         }
 
-        // after the validation, we can call the actual build method to create the new Fenrir instance
-        self.build()
+        assert_eq!(fenrir.dropped_entries(), 1);
+        assert_eq!(
+            *dropped.read(),
+            vec![("The buffer for log messages is full".to_string(), 1)]
+        );
+        let lines = buffered_lines(&fenrir);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("first") && lines[1].contains("second"));
     }
 
-    /// Create a new `Fenrir` instance with the parameters supplied to this struct before calling this method.
-    ///
-    /// # Note
-    /// If an async network backend is selected, this method will panic if no runtime handle was set,
-    /// and this method is called outside the context of a Tokio 1.x runtime.
-    ///
-    /// # Example
-    /// ```
-    /// use url::Url;
-    /// use fenrir_rs::{Fenrir, NetworkingBackend, SerializationFormat};
-    ///
-    /// let fenrir = Fenrir::builder()
-    ///     .endpoint(Url::parse("https://loki.example.com").unwrap())
-    ///     .network(NetworkingBackend::Ureq)
-    ///     .format(SerializationFormat::Json)
-    ///     .build();
-    /// ```
-    pub fn build(self) -> Fenrir {
-        use crate::noop::NoopBackend;
+    #[test]
+    fn the_oldest_entries_are_dropped_if_requested() {
+        let fenrir = Fenrir::builder()
+            .max_buffered_entries(2)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .build();
+        for message in ["first", "second", "third"] {
+            fenrir.log(&Record::builder().args(format_args!("{}", message)).build());
+        }
 
-        // panic if the number of logs to buffer is 0 (will cause infinite memory growth otherwise)
-        if self.flush_threshold == 0 {
-            panic!("You have to set a buffer size greater than 0");
+        assert_eq!(fenrir.dropped_entries(), 1);
+        let lines = buffered_lines(&fenrir);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("second") && lines[1].contains("third"));
+    }
+
+    #[test]
+    fn a_full_buffer_is_flushed_if_the_logging_thread_should_block() {
+        let backend = ThreadRecordingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(backend.clone()))
+            .max_buffered_entries(2)
+            // waiting without a deadline must not overflow
+            .overflow_policy(OverflowPolicy::Block(Duration::MAX))
+            .build();
+        for message in ["first", "second", "third"] {
+            fenrir.log(&Record::builder().args(format_args!("{}", message)).build());
         }
 
-        // create the instance of the required network backend
-        let network_backend: Box<dyn FenrirBackend + Send + Sync> = match self.network_backend {
-            NetworkingBackend::None => Box::new(NoopBackend {}),
+        assert_eq!(fenrir.dropped_entries(), 0);
+        let lines = buffered_lines(&fenrir);
+        assert!(lines.len() == 1 && lines[0].contains("third"));
 
-            #[cfg(feature = "ureq")]
-            NetworkingBackend::Ureq => Box::new(crate::ureq::UreqBackend {
-                authentication: self.authentication,
-                credentials: self.credentials,
-                endpoint: self.endpoint,
-            }),
+        // the buffer was flushed by the worker thread instead of the logging thread
+        fenrir.shutdown(Duration::from_secs(1));
+        assert_eq!(*backend.0.read(), vec![Some("fenrir-flush".to_string())]);
+    }
 
-            #[cfg(feature = "reqwest-async")]
-            NetworkingBackend::Reqwest => Box::new(crate::reqwest::ReqwestBackend {
-                authentication: self.authentication,
-                credentials: self.credentials,
-                endpoint: self.endpoint,
-                client: ::reqwest::Client::new(),
-                runtime_handle: self.runtime.unwrap_or_else(tokio::runtime::Handle::current),
-            }),
+    #[test]
+    fn an_entry_which_never_fits_into_the_buffer_does_not_evict_other_entries() {
+        let fenrir = Fenrir::builder()
+            .max_buffered_bytes(1000)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .build();
+        let large = "x".repeat(2000);
+        for message in ["first", "second", large.as_str()] {
+            fenrir.log(&Record::builder().args(format_args!("{}", message)).build());
+        }
+
+        assert_eq!(fenrir.dropped_entries(), 1);
+        let lines = buffered_lines(&fenrir);
+        assert!(lines.len() == 2 && lines[0].contains("first") && lines[1].contains("second"));
+    }
+
+    #[test]
+    fn the_size_of_the_buffered_log_lines_is_limited() {
+        let limits = BufferLimits {
+            max_bytes: Some(10),
+            ..BufferLimits::default()
+        };
+        let entry = |line: &str| LogEntry {
+            timestamp: Duration::ZERO,
+            line: line.to_string(),
+            metadata: BTreeMap::new(),
         };
 
-        // determine the serialization function to use
-        let serializer = match self.serialization_format {
-            SerializationFormat::None => noop_serializer,
+        let mut buffer = LogBuffer::default();
+        assert!(limits.fits(&buffer, 10));
+        assert!(!limits.fits(&buffer, 11));
+        buffer.push(None, BTreeMap::new(), entry("123456"));
+        assert!(limits.fits(&buffer, 4));
+        assert!(!limits.fits(&buffer, 5));
+        assert_eq!(buffer.pop_oldest(), Some(6));
+        assert_eq!(buffer.size, 0);
+        assert!(limits.fits(&buffer, 10));
+        assert_eq!(buffer.pop_oldest(), None);
+        assert!(buffer.take().0.is_empty());
+    }
 
-            #[cfg(feature = "json")]
-            SerializationFormat::Json => |data: &Streams| -> Result<Vec<u8>, String> {
-                serde_json::to_vec(data).map_err(|error| error.to_string())
-            },
+    #[test]
+    fn the_oldest_entry_is_popped_across_all_streams() {
+        let labels = |app: &str| BTreeMap::from([("app".to_string(), app.to_string())]);
+        let entry = |line: &str| LogEntry {
+            timestamp: Duration::ZERO,
+            line: line.to_string(),
+            metadata: BTreeMap::new(),
         };
 
-        // create and return the actual backend
-        Fenrir {
-            backend: network_backend,
-            serializer,
-            include_level: self.include_level,
-            include_framework: self.include_framework,
-            additional_tags: self.additional_tags,
-            log_stream: RwLock::new(Vec::with_capacity(self.flush_threshold)),
-            flush_threshold: self.flush_threshold,
-            max_message_size: self.max_message_size,
-        }
+        let mut buffer = LogBuffer::default();
+        buffer.push(None, labels("a"), entry("1"));
+        buffer.push(None, labels("b"), entry("22"));
+        buffer.push(None, labels("a"), entry("333"));
+        assert_eq!(buffer.pop_oldest(), Some(1));
+        assert_eq!(buffer.pop_oldest(), Some(2));
+        assert!(buffer.oldest_entry.is_some());
+
+        let (streams, entries) = buffer.take();
+        assert_eq!(entries, 1);
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].values, vec![entry("333")]);
     }
-}
 
-/// A serialization implementation which does nothing when requesting to serialize a object
-pub(crate) fn noop_serializer(_: &Streams) -> Result<Vec<u8>, String> {
-    Ok(vec![])
-}
+    #[test]
+    fn an_emptied_buffer_has_no_oldest_entry() {
+        let entry = LogEntry {
+            timestamp: Duration::ZERO,
+            line: "message".to_string(),
+            metadata: BTreeMap::new(),
+        };
 
-/// A struct for visiting all structured logging labels of a log message and collecting them
-#[cfg(feature = "structured_logging")]
-struct LokiVisitor<'kvs> {
-    values: HashMap<log::kv::Key<'kvs>, log::kv::Value<'kvs>>,
-}
+        let mut buffer = LogBuffer::default();
+        buffer.push(None, BTreeMap::new(), entry.clone());
+        assert!(buffer.oldest_entry.is_some());
+        assert_eq!(buffer.pop_oldest(), Some(7));
+        assert_eq!(buffer.oldest_entry, None);
 
-/// The implementation for the visitor pattern for collecting all labels attached to a log
-/// message
-#[cfg(feature = "structured_logging")]
-impl<'kvs> LokiVisitor<'kvs> {
-    /// Create a new visitor with an initial capacity of `count` labels.
-    pub fn new(count: usize) -> Self {
-        Self {
-            values: HashMap::with_capacity(count),
+        // the age of a new entry is measured from the time it was added
+        let before_push = Instant::now();
+        buffer.push(None, BTreeMap::new(), entry);
+        assert!(buffer.oldest_entry.unwrap() >= before_push);
+    }
+
+    #[test]
+    fn streams_are_split_into_batches_of_limited_size() {
+        let entry = |line: &str| LogEntry {
+            timestamp: Duration::ZERO,
+            line: line.to_string(),
+            metadata: BTreeMap::new(),
+        };
+        let stream = |label: &str, lines: &[&str]| Stream {
+            stream: BTreeMap::from([("app".to_string(), label.to_string())]),
+            values: lines.iter().map(|line| entry(line)).collect(),
+            tenant: None,
+        };
+        let streams = vec![stream("a", &["1", "2", "3"]), stream("b", &["4"])];
+        let labels_size = streams[0].labels_size();
+        let entry_size = entry("1").approximate_size();
+
+        let chunks = split_by_size(streams, labels_size + 2 * entry_size);
+        let lines = chunks
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .map(|stream| (stream.stream["app"].clone(), stream.values.len()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                vec![("a".to_string(), 2)],
+                vec![("a".to_string(), 1)],
+                vec![("b".to_string(), 1)],
+            ]
+        );
+
+        // entries which are too large on their own are not dropped
+        let chunks = split_by_size(vec![stream("a", &["1", "2"])], 1);
+        assert_eq!(chunks.len(), 2);
+    }
+
+    #[test]
+    fn the_buffer_is_flushed_before_a_batch_exceeds_its_maximum_size() {
+        let backend = RecordingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(backend.clone()))
+            .max_batch_bytes(1)
+            .build();
+        for message in ["first", "second", "third"] {
+            fenrir.log(&Record::builder().args(format_args!("{}", message)).build());
         }
+
+        assert_eq!(backend.tenants(), vec![(None, 1), (None, 1)]);
+        assert_eq!(buffered_lines(&fenrir).len(), 1);
     }
 
-    /// Read the key-value-pair attached to a log message and collect them
-    pub fn read_kv(
-        &'kvs mut self,
-        source: &'kvs dyn Source,
-    ) -> Result<&HashMap<log::kv::Key<'kvs>, log::kv::Value<'kvs>>, log::kv::Error> {
-        for _ in 0..source.count() {
-            source.visit(self)?;
+    #[test]
+    fn batches_which_are_too_large_for_loki_are_split() {
+        let backend = RecordingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(SizeLimitedBackend(1, backend.clone())))
+            .build();
+        for message in ["first", "second", "third", "fourth"] {
+            fenrir.log(&Record::builder().args(format_args!("{}", message)).build());
         }
-        Ok(&self.values)
+        fenrir.flush();
+
+        assert_eq!(backend.tenants(), vec![(None, 1); 4]);
+        assert_eq!(fenrir.shutdown(Duration::ZERO), 0);
+
+        // single log entries cannot be split any further
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(SizeLimitedBackend(0, backend)))
+            .build();
+        fenrir.log(&Record::builder().args(format_args!("message")).build());
+        fenrir.flush();
+        assert_eq!(fenrir.shutdown(Duration::ZERO), 1);
     }
-}
 
-/// The [`Visitor'] implementation for the [`LokiVisitor`]
-#[cfg(feature = "structured_logging")]
-impl<'kvs> Visitor<'kvs> for LokiVisitor<'kvs> {
-    fn visit_pair(
-        &mut self,
-        key: log::kv::Key<'kvs>,
-        value: log::kv::Value<'kvs>,
-    ) -> Result<(), log::kv::Error> {
-        self.values.insert(key, value);
-        Ok(())
+    #[test]
+    #[should_panic(expected = "Max batch bytes must be greater than 0")]
+    fn a_maximum_batch_size_of_zero_panics() {
+        let _builder = Fenrir::builder().max_batch_bytes(0);
     }
-}
 
-/// The data structure used for attaching tags / labels to logging entries before sending them
-/// to Loki
-#[derive(Serialize)]
-pub(crate) struct Stream {
-    /// The tags which should be attached to the logging entries
-    pub(crate) stream: HashMap<String, String>,
-    /// The actual log messages to store with the corresponding meta information
-    pub(crate) values: Vec<Vec<String>>,
-}
+    #[test]
+    fn batches_which_could_not_be_delivered_are_spooled() {
+        let dir = std::env::temp_dir().join(format!("fenrir-spool-{}", std::process::id()));
+        let reported = Arc::new(AtomicUsize::new(0));
+        let callback_reported = reported.clone();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(FailingBackend))
+            .spool_dir(&dir, 1024 * 1024)
+            .on_error(move |_, _| {
+                callback_reported.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+        fenrir.log(&Record::builder().args(format_args!("message")).build());
 
-/// The data structure used for encoding a single log message before sending it to Loki
-#[derive(Serialize)]
-#[cfg(feature = "json-log-fmt")]
-pub(crate) struct SerializedEvent<'a> {
-    /// The file name of the log message source
-    pub(crate) file: Option<&'a str>,
-    /// The line number of the log message source
-    pub(crate) line: Option<u32>,
-    /// The module name of the log message source
-    pub(crate) module: Option<&'a str>,
-    /// The log level of the log message
-    pub(crate) level: &'static str,
-    /// The target of the log message
-    pub(crate) target: &'a str,
-    /// The actual log message
-    pub(crate) message: String,
-}
+        assert_eq!(fenrir.shutdown(Duration::ZERO), 0);
+        assert_eq!(reported.load(Ordering::SeqCst), 0);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        drop(fenrir);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-/// The base data structure Loki expects when receiving logging messages.
-#[derive(Serialize)]
-pub(crate) struct Streams<'a> {
-    /// A list of all logging messages with the attached meta information which should be logged
-    /// by Loki
-    pub(crate) streams: &'a [Stream],
-}
+    #[test]
+    fn cloned_batches_share_their_payload() {
+        let batch = Batch::new(b"payload".to_vec(), "application/json", 1);
+        let kept = batch.clone();
+        assert!(Arc::ptr_eq(&batch.payload, &kept.payload));
+        assert_eq!(batch.into_payload(), b"payload");
 
-#[cfg(test)]
-mod tests {
-    use crate::{Fenrir, NetworkingBackend, SerializationFormat};
+        // the payload is not copied if the batch is not shared anymore
+        let data = kept.payload().as_ptr();
+        let payload = kept.into_payload();
+        assert_eq!(payload.as_ptr(), data);
+    }
 
     #[test]
-    #[should_panic]
-    fn building_a_validated_fenrir_instance_without_network_backend_panics() {
-        let _fenrir = Fenrir::builder()
-            .format(SerializationFormat::Json)
-            .build_with_validation();
+    #[should_panic(expected = "Max spool size must be greater than 0")]
+    fn a_spool_without_space_panics() {
+        let _builder = Fenrir::builder().spool_dir("spool", 0);
     }
 
     #[test]
-    fn building_a_non_validated_fenrir_instance_without_network_backend_does_not_panic() {
-        let _fenrir = Fenrir::builder().format(SerializationFormat::Json).build();
+    #[should_panic(expected = "Could not send 1 log entries to Loki")]
+    fn undeliverable_entries_panic_if_requested() {
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(FailingBackend))
+            .panic_on_error()
+            .build();
+        fenrir.log(&Record::builder().args(format_args!("message")).build());
+        fenrir.flush();
+    }
+
+    /// A backend which refuses to send anything and counts how often it was asked to
+    #[derive(Clone, Default)]
+    struct CountingFailingBackend(Arc<AtomicUsize>);
+
+    impl FenrirBackend for CountingFailingBackend {
+        fn send(&self, batch: Batch) -> Result<(), FenrirError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            FailingBackend.send(batch)
+        }
     }
 
     #[test]
-    #[should_panic]
-    fn building_a_validated_fenrir_instance_without_serialization_backend_panics() {
-        let _fenrir = Fenrir::builder()
-            .network(NetworkingBackend::Ureq)
-            .build_with_validation();
+    fn entries_are_flushed_by_the_logging_thread_if_the_worker_panicked() {
+        let backend = CountingFailingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(backend.clone()))
+            .flush_threshold(1)
+            .background_dispatch()
+            .panic_on_error()
+            .build();
+
+        // the worker thread panics since the entry cannot be delivered
+        fenrir.log(&Record::builder().args(format_args!("first")).build());
+        assert!(wait_until(|| fenrir.shipper.worker_signal.has_panicked()));
+        assert_eq!(backend.0.load(Ordering::SeqCst), 1);
+
+        // afterward, the logging thread flushes the buffer (and panics on its own)
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            fenrir.log(&Record::builder().args(format_args!("second")).build());
+        }));
+        assert!(result.is_err());
+        assert_eq!(backend.0.load(Ordering::SeqCst), 2);
+        assert!(fenrir.shipper.log_stream.lock().is_empty());
     }
 
     #[test]
-    fn building_a_non_validated_fenrir_instance_without_serialization_backend_does_not_panic() {
-        let _fenrir = Fenrir::builder().network(NetworkingBackend::Ureq).build();
+    fn due_entries_are_flushed_by_the_logging_thread_if_the_interval_worker_panicked() {
+        let backend = CountingFailingBackend::default();
+        let fenrir = Fenrir::builder()
+            .custom_backend(Box::new(backend.clone()))
+            .flush_interval(Duration::from_millis(10))
+            .panic_on_error()
+            .build();
+
+        fenrir.log(&Record::builder().args(format_args!("first")).build());
+        assert!(wait_until(|| fenrir.shipper.worker_signal.has_panicked()));
+
+        // the entry is not due yet, so it stays in the buffer until the next log message
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            fenrir.log(&Record::builder().args(format_args!("second")).build());
+            std::thread::sleep(Duration::from_millis(20));
+            fenrir.log(&Record::builder().args(format_args!("third")).build());
+        }));
+        assert!(result.is_err());
+        assert_eq!(backend.0.load(Ordering::SeqCst), 2);
+        assert!(fenrir.shipper.log_stream.lock().is_empty());
+    }
+
+    #[test]
+    fn messages_are_filtered_by_the_configured_levels() {
+        use log::{Level, LevelFilter, Metadata};
+
+        let fenrir = Fenrir::builder()
+            .max_level(LevelFilter::Info)
+            .level_for("hyper", LevelFilter::Warn)
+            .build();
+        let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+
+        assert!(fenrir.enabled(&metadata(Level::Info, "app")));
+        assert!(!fenrir.enabled(&metadata(Level::Debug, "app")));
+        assert!(!fenrir.enabled(&metadata(Level::Info, "hyper::client")));
+        assert!(fenrir.enabled(&metadata(Level::Warn, "hyper::client")));
+    }
+
+    #[test]
+    fn invalid_filter_directives_from_the_environment_are_ignored() {
+        use log::LevelFilter;
+
+        std::env::set_var("FENRIR_TEST_INVALID_LOG", "warn,hyper=loud,app=debug");
+        let builder = Fenrir::builder().filter_from_env("FENRIR_TEST_INVALID_LOG");
+
+        assert_eq!(builder.filter.max_level, LevelFilter::Warn);
+        assert_eq!(builder.filter.level_for("hyper"), LevelFilter::Warn);
+        assert_eq!(builder.filter.level_for("app"), LevelFilter::Debug);
+
+        // the invalid directives are reported as soon as the error handler exists
+        let warnings = Arc::new(RwLock::new(Vec::new()));
+        let callback_warnings = warnings.clone();
+        let _fenrir = builder
+            .on_error(move |error, info| {
+                callback_warnings
+                    .write()
+                    .push((error.to_string(), info.entries));
+            })
+            .build();
+        assert_eq!(
+            *warnings.read(),
+            vec![(
+                "Invalid configuration: ignoring the invalid filter directive in \
+                 FENRIR_TEST_INVALID_LOG: invalid level in directive 'hyper=loud'"
+                    .to_string(),
+                0
+            )]
+        );
     }
 }
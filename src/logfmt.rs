@@ -0,0 +1,148 @@
+//! A module which contains the encoder for log lines in the logfmt format (e.g.
+//! `level=info target=app msg="Hello World"`), which can be parsed by the `| logfmt` expression
+//! of LogQL.
+use log::Record;
+use std::collections::HashSet;
+
+/// The keys of the fields containing the meta information of a log line
+const RESERVED_KEYS: [&str; 5] = ["level", "target", "msg", "file", "line"];
+
+/// Encode the `record` with its formatted `message` and the additional `fields` as a logfmt line
+pub(crate) fn encode_line(record: &Record, message: &str, fields: &[(String, String)]) -> String {
+    let mut line = String::with_capacity(message.len() + 64);
+    push_field(&mut line, "level", &record.level().as_str().to_lowercase());
+    push_field(&mut line, "target", record.target());
+    push_field(&mut line, "msg", message);
+    if let Some(file) = record.file() {
+        push_field(&mut line, "file", file);
+    }
+    if let Some(number) = record.line() {
+        push_field(&mut line, "line", &number.to_string());
+    }
+
+    // a key must not be repeated, since parsers would only keep one of the values
+    let mut emitted = RESERVED_KEYS
+        .iter()
+        .map(|key| key.to_string())
+        .collect::<HashSet<_>>();
+    for (key, value) in fields {
+        let mut key = sanitize_key(key);
+        while emitted.contains(&key) {
+            key.insert(0, '_');
+        }
+        push_field(&mut line, &key, value);
+        emitted.insert(key);
+    }
+    line
+}
+
+/// Replace all characters of the `key` which would end it, since a key cannot be quoted
+fn sanitize_key(key: &str) -> String {
+    key.chars()
+        .map(|c| match needs_quotes(c) {
+            true => '_',
+            false => c,
+        })
+        .collect()
+}
+
+/// Append a single `key=value` pair to the `line` (the value is quoted if required, the key has
+/// to be sanitized already)
+fn push_field(line: &mut String, key: &str, value: &str) {
+    if !line.is_empty() {
+        line.push(' ');
+    }
+    line.push_str(key);
+    line.push('=');
+
+    if !value.chars().any(needs_quotes) {
+        line.push_str(value);
+        return;
+    }
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => line.push_str(&format!("\\u{:04x}", c as u32)),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// Check if a value containing the character `c` has to be quoted
+fn needs_quotes(c: char) -> bool {
+    c <= ' ' || c == '=' || c == '"' || c == '\\' || c.is_control()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logfmt::{encode_line, push_field};
+    use log::{Level, Record};
+
+    #[test]
+    fn values_are_only_quoted_if_required() {
+        let mut line = String::new();
+        push_field(&mut line, "plain", "value");
+        push_field(&mut line, "spaces", "two words");
+        push_field(&mut line, "empty", "");
+        push_field(&mut line, "escaped", "a \"quote\", a \\ and a\nnewline");
+        push_field(&mut line, "equals", "a=b");
+        assert_eq!(
+            line,
+            "plain=value spaces=\"two words\" empty= \
+             escaped=\"a \\\"quote\\\", a \\\\ and a\\nnewline\" equals=\"a=b\""
+        );
+    }
+
+    #[test]
+    fn records_are_encoded_with_their_meta_information() {
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("app::db")
+            .file(Some("src/db.rs"))
+            .line(Some(42))
+            .build();
+        let fields = vec![("user_id".to_string(), "4711".to_string())];
+        assert_eq!(
+            encode_line(&record, "Slow query", &fields),
+            "level=warn target=app::db msg=\"Slow query\" file=src/db.rs line=42 user_id=4711"
+        );
+
+        let record = Record::builder().level(Level::Info).target("app").build();
+        assert_eq!(
+            encode_line(&record, "Started", &[]),
+            "level=info target=app msg=Started"
+        );
+    }
+
+    #[test]
+    fn fields_do_not_repeat_the_keys_of_the_meta_information() {
+        let record = Record::builder().level(Level::Info).target("app").build();
+        let fields = vec![
+            ("_level".to_string(), "a".to_string()),
+            ("level".to_string(), "b".to_string()),
+            ("msg".to_string(), "c".to_string()),
+        ];
+        assert_eq!(
+            encode_line(&record, "Started", &fields),
+            "level=info target=app msg=Started _level=a __level=b _msg=c"
+        );
+
+        // keys are compared after the characters which would end them were replaced
+        let fields = vec![
+            (" level".to_string(), "a".to_string()),
+            ("a b".to_string(), "b".to_string()),
+            ("a_b".to_string(), "c".to_string()),
+            ("level".to_string(), "d".to_string()),
+        ];
+        assert_eq!(
+            encode_line(&record, "Started", &fields),
+            "level=info target=app msg=Started _level=a a_b=b _a_b=c __level=d"
+        );
+    }
+}
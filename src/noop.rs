@@ -1,13 +1,13 @@
 //! A module which contains the implementation for the [`FenrirBackend`] trait which ignores all
 //! network requests.
-use crate::{AuthenticationMethod, FenrirBackend};
+use crate::{AuthenticationMethod, Batch, FenrirBackend, FenrirError};
 use std::any::TypeId;
 
 /// The [`NoopBackend`] is used by default and does ignore all logging messages.
 pub(crate) struct NoopBackend;
 
 impl FenrirBackend for NoopBackend {
-    fn send(&self, _: Vec<u8>) -> Result<(), String> {
+    fn send(&self, _: Batch) -> Result<(), FenrirError> {
         Ok(())
     }
 
@@ -41,12 +41,12 @@ mod tests {
             .format(SerializationFormat::Json)
             .build();
         assert_eq!(
-            result.backend.authentication_method(),
+            result.shipper.backend.authentication_method(),
             AuthenticationMethod::None
         );
-        assert_eq!(result.backend.credentials(), None);
+        assert_eq!(result.shipper.backend.credentials(), None);
         assert_eq!(
-            result.backend.internal_type(),
+            result.shipper.backend.internal_type(),
             TypeId::of::<NoopBackend>().type_id()
         );
     }
@@ -64,12 +64,12 @@ mod tests {
             )
             .build();
         assert_eq!(
-            result.backend.authentication_method(),
+            result.shipper.backend.authentication_method(),
             AuthenticationMethod::None
         );
-        assert_eq!(result.backend.credentials(), None);
+        assert_eq!(result.shipper.backend.credentials(), None);
         assert_eq!(
-            result.backend.internal_type(),
+            result.shipper.backend.internal_type(),
             TypeId::of::<NoopBackend>().type_id()
         );
     }
@@ -0,0 +1,165 @@
+//! A module which contains the serialization of the logging messages into the snappy-compressed
+//! protobuf format (`logproto.PushRequest`) which is natively used by Loki.
+use crate::{FenrirError, LogEntry, Stream, Streams};
+use prost::Message;
+use std::collections::BTreeMap;
+
+/// The `logproto.PushRequest` message which is the root message of a push to Loki
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct PushRequest {
+    /// All streams which should be pushed to Loki
+    #[prost(message, repeated, tag = "1")]
+    pub(crate) streams: Vec<StreamAdapter>,
+}
+
+/// The `logproto.StreamAdapter` message which contains all entries with the same label set
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct StreamAdapter {
+    /// The labels of the stream, encoded like a Prometheus series selector (`{key="value"}`)
+    #[prost(string, tag = "1")]
+    pub(crate) labels: String,
+    /// The entries of the stream
+    #[prost(message, repeated, tag = "2")]
+    pub(crate) entries: Vec<EntryAdapter>,
+}
+
+/// The `logproto.EntryAdapter` message which contains a single log line
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct EntryAdapter {
+    /// The time the log line was logged at
+    #[prost(message, optional, tag = "1")]
+    pub(crate) timestamp: Option<Timestamp>,
+    /// The actual log line
+    #[prost(string, tag = "2")]
+    pub(crate) line: String,
+    /// The structured metadata attached to the log line
+    #[prost(message, repeated, tag = "3")]
+    pub(crate) structured_metadata: Vec<LabelPairAdapter>,
+}
+
+/// The `logproto.LabelPairAdapter` message which contains a single entry of the structured
+/// metadata of a log line
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct LabelPairAdapter {
+    /// The key of the entry
+    #[prost(string, tag = "1")]
+    pub(crate) name: String,
+    /// The value of the entry
+    #[prost(string, tag = "2")]
+    pub(crate) value: String,
+}
+
+/// The `google.protobuf.Timestamp` message
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Timestamp {
+    /// The seconds since the UNIX epoch
+    #[prost(int64, tag = "1")]
+    pub(crate) seconds: i64,
+    /// The fraction of the second in nanoseconds
+    #[prost(int32, tag = "2")]
+    pub(crate) nanos: i32,
+}
+
+impl From<&LogEntry> for EntryAdapter {
+    fn from(entry: &LogEntry) -> Self {
+        EntryAdapter {
+            timestamp: Some(Timestamp {
+                seconds: entry.timestamp.as_secs() as i64,
+                nanos: entry.timestamp.subsec_nanos() as i32,
+            }),
+            line: entry.line.clone(),
+            structured_metadata: entry
+                .metadata
+                .iter()
+                .map(|(name, value)| LabelPairAdapter {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&Stream> for StreamAdapter {
+    fn from(stream: &Stream) -> Self {
+        StreamAdapter {
+            labels: encode_labels(&stream.stream),
+            entries: stream.values.iter().map(EntryAdapter::from).collect(),
+        }
+    }
+}
+
+/// Encode the labels of a stream the way Loki expects them (e.g. `{level="INFO", service="app"}`)
+fn encode_labels(labels: &BTreeMap<String, String>) -> String {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(", "))
+}
+
+/// Serialize the streams into a snappy-compressed `logproto.PushRequest`
+pub(crate) fn serialize(data: &Streams) -> Result<Vec<u8>, FenrirError> {
+    let request = PushRequest {
+        streams: data.streams.iter().map(StreamAdapter::from).collect(),
+    };
+    snap::raw::Encoder::new()
+        .compress_vec(&request.encode_to_vec())
+        .map_err(|error| FenrirError::Serialization(Box::new(error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protobuf::{encode_labels, serialize, PushRequest};
+    use crate::{LogEntry, Stream, Streams};
+    use prost::Message;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::time::Duration;
+
+    #[test]
+    fn labels_are_encoded_as_a_sorted_series_selector() {
+        let labels = BTreeMap::from([
+            ("service".to_string(), "app".to_string()),
+            ("level".to_string(), "say \"hi\"".to_string()),
+        ]);
+        assert_eq!(
+            encode_labels(&labels),
+            r#"{level="say \"hi\"", service="app"}"#
+        );
+    }
+
+    #[test]
+    fn serialized_streams_can_be_decoded_again() {
+        let streams = [Stream {
+            stream: BTreeMap::from([("service".to_string(), "app".to_string())]),
+            values: VecDeque::from([LogEntry {
+                timestamp: Duration::new(1_700_000_000, 42),
+                line: "message".to_string(),
+                metadata: BTreeMap::from([("request_id".to_string(), "4711".to_string())]),
+            }]),
+            tenant: None,
+        }];
+
+        let serialized = serialize(&Streams { streams: &streams }).unwrap();
+        let decompressed = snap::raw::Decoder::new()
+            .decompress_vec(&serialized)
+            .unwrap();
+        let request = PushRequest::decode(decompressed.as_slice()).unwrap();
+
+        assert_eq!(request.streams.len(), 1);
+        assert_eq!(request.streams[0].labels, r#"{service="app"}"#);
+        let entry = &request.streams[0].entries[0];
+        assert_eq!(entry.line, "message");
+        assert_eq!(entry.timestamp.as_ref().unwrap().seconds, 1_700_000_000);
+        assert_eq!(entry.timestamp.as_ref().unwrap().nanos, 42);
+        assert_eq!(entry.structured_metadata.len(), 1);
+        assert_eq!(entry.structured_metadata[0].name, "request_id");
+        assert_eq!(entry.structured_metadata[0].value, "4711");
+    }
+}
@@ -1,70 +1,158 @@
 //! A module which contains the implementation for the [`FenrirBackend`] trait which uses the `reqwest`
 //! crate for network communication.
 
-use crate::{AuthenticationMethod, FenrirBackend};
-use reqwest::Client;
+use crate::credentials::CredentialCache;
+use crate::error::ErrorHandler;
+use crate::retry::parse_retry_after;
+use crate::spool::Spool;
+use crate::{
+    settle_delivery, AuthenticationMethod, Batch, Encoder, FenrirBackend, FenrirError, RetryPolicy,
+};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+use reqwest::{Client, RequestBuilder};
 use std::any::TypeId;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
+/// Keeps track of the log entries which are sent by background tasks.
+#[derive(Default)]
+pub(crate) struct InFlight {
+    /// The state of the log entries sent in the background
+    state: Mutex<InFlightState>,
+    /// Notified every time a background task finished
+    finished: Condvar,
+}
+
+/// The number of log entries which are currently sent or could not be sent by background tasks
+#[derive(Default)]
+struct InFlightState {
+    /// The number of log entries which are currently being sent
+    pending: usize,
+    /// The number of log entries which could not be delivered
+    failed: usize,
+}
+
+impl InFlight {
+    /// Register a new batch with `entries` log entries which will be sent in the background
+    fn start(&self, entries: usize) {
+        self.state.lock().pending += entries;
+    }
+
+    /// Mark the sending of `entries` log entries as finished (`dropped` of them could not be
+    /// delivered)
+    fn finish(&self, entries: usize, dropped: usize) {
+        let mut state = self.state.lock();
+        state.pending -= entries;
+        state.failed += dropped;
+        self.finished.notify_all();
+    }
+
+    /// Wait up to `timeout` for all batches to be sent and return the number of log entries
+    /// which are still pending or could not be delivered
+    fn wait(&self, timeout: Duration) -> usize {
+        let mut state = self.state.lock();
+        self.finished
+            .wait_while_for(&mut state, |state| state.pending > 0, timeout);
+        state.pending + state.failed
+    }
+}
+
+/// The payload of a [`Batch`] which is used as the body of a request without copying it
+struct SharedPayload(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedPayload {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 /// A [`FenrirBackend`] implementation which uses the [reqwest](https://crates.io/crates/reqwest) crate to
 /// send logging messages to a Loki endpoint.
 pub(crate) struct ReqwestBackend {
-    /// The loki endpoint which is used to send log information to
-    pub(crate) endpoint: Url,
-    /// The authentication method to use when sending the log messages to the remote [`UreqBackend::endpoint`]
+    /// The URL of the push API of the Loki endpoint which is used to send log information to
+    pub(crate) push_url: Url,
+    /// The authentication method to use when sending the log messages to the remote [`ReqwestBackend::push_url`]
     pub(crate) authentication: AuthenticationMethod,
-    /// The credentials to use to authenticate against the remote [`UreqBackend::endpoint`]
+    /// The credentials to use to authenticate against the remote [`ReqwestBackend::push_url`]
     pub(crate) credentials: String,
     /// Internal client
     pub(crate) client: Client,
     /// Runtime handle
     pub(crate) runtime_handle: tokio::runtime::Handle,
+    /// The log entries which are currently sent by background tasks
+    pub(crate) in_flight: Arc<InFlight>,
+    /// The handler which is informed about log entries which could not be delivered
+    pub(crate) error_handler: Arc<ErrorHandler>,
+    /// The policy which decides if and when failed requests are retried
+    pub(crate) retry_policy: RetryPolicy,
+    /// Additional HTTP headers which are sent with each request
+    pub(crate) headers: Vec<(String, String)>,
+    /// The cache for the credentials of the configured provider (if any)
+    pub(crate) credential_cache: Option<Arc<CredentialCache>>,
+    /// The spool which keeps batches which could not be delivered on disk (if configured)
+    pub(crate) spool: Option<Arc<Spool>>,
+    /// The encoder used for serializing the parts of batches which were rejected as too large
+    pub(crate) encoder: Encoder,
 }
 
 impl FenrirBackend for ReqwestBackend {
-    fn send(&self, serialized_streams: Vec<u8>) -> Result<(), String> {
-        let post_url: Url = self
-            .endpoint
-            .clone()
-            .join("/loki/api/v1/push")
-            .map_err(|e| e.to_string())?;
-        let mut builder = self
-            .client
-            .post(post_url)
-            .header("Content-Type", "application/json; charset=utf-8");
-        if let AuthenticationMethod::Basic = self.authentication {
-            builder = builder.header(
-                "Authorization",
-                format!("Basic {}", self.credentials).as_str(),
-            );
+    fn send(&self, mut batch: Batch) -> Result<(), FenrirError> {
+        let push_client = PushClient {
+            client: self.client.clone(),
+            push_url: self.push_url.clone(),
+            headers: self.headers.clone(),
+            retry_policy: self.retry_policy.clone(),
+            credential_cache: self.credential_cache.clone(),
+            authorization: self.authentication.authorization(&self.credentials),
+        };
+
+        // batches replayed from the spool stay there until the replay learns about the result
+        if let Some(receipt) = batch.receipt.take() {
+            receipt.claim();
+            self.runtime_handle.spawn(async move {
+                receipt.complete(push_client.push(batch).await);
+            });
+            return Ok(());
         }
-        builder = builder.body(serialized_streams);
-        let runtime_handle = self.runtime_handle.clone();
-        runtime_handle.spawn(async move {
-            // retry 3 times if failed with a non-400 error
-            let mut retry_count = 0;
-            loop {
-                let b2 = builder.try_clone().expect("should be able to clone");
-                let res = builder.send().await;
-                match res {
-                    Ok(_) => {
-                        break;
-                    }
-                    Err(e) => {
-                        if e.status().map_or(false, |x| x.is_client_error()) || retry_count >= 3 {
-                            log::error!("Failed to send logs to Loki: {}", e);
-                            break;
-                        }
-                        retry_count += 1;
-                    }
+
+        let encoder = self.encoder;
+        let spool = self.spool.clone();
+        let in_flight = self.in_flight.clone();
+        let error_handler = self.error_handler.clone();
+        in_flight.start(batch.entries);
+        self.runtime_handle.spawn(async move {
+            let mut batches = VecDeque::from([batch]);
+            while let Some(batch) = batches.pop_front() {
+                let entries = batch.entries;
+                // the request consumes the batch, so a copy (sharing its payload) is kept for
+                // settling it
+                let result = push_client.push(batch.clone()).await;
+                let settlement =
+                    settle_delivery(result, batch, &encoder, spool.as_deref(), &error_handler);
+
+                // the entries of the parts stay in flight until the parts were sent
+                let resent = settlement
+                    .resend
+                    .iter()
+                    .map(|part| part.entries)
+                    .sum::<usize>();
+                in_flight.finish(entries - resent, settlement.dropped);
+                for part in settlement.resend.into_iter().rev() {
+                    batches.push_front(part);
                 }
-                builder = b2;
             }
         });
 
         Ok(())
     }
 
+    fn shutdown(&self, timeout: Duration) -> usize {
+        self.in_flight.wait(timeout)
+    }
+
     fn internal_type(&self) -> TypeId {
         use std::any::Any;
 
@@ -76,20 +164,194 @@ impl FenrirBackend for ReqwestBackend {
     }
 
     fn credentials(&self) -> Option<String> {
-        if self.credentials.len() > 0 {
+        if !self.credentials.is_empty() {
             return Some(self.credentials.clone());
         }
         None
     }
+
+    fn excluded_targets(&self) -> &'static [&'static str] {
+        &[
+            "reqwest",
+            "hyper",
+            "hyper_util",
+            "h2",
+            "rustls",
+            "tokio_rustls",
+        ]
+    }
+}
+
+/// The [`PushClient`] contains everything which is required to send a single [`Batch`] to the
+/// push API of Loki.
+struct PushClient {
+    /// The client used for sending the requests
+    client: Client,
+    /// The URL of the push API of the Loki endpoint
+    push_url: Url,
+    /// Additional HTTP headers which are sent with each request
+    headers: Vec<(String, String)>,
+    /// The policy which decides if and when failed requests are retried
+    retry_policy: RetryPolicy,
+    /// The cache for the credentials of the configured provider (if any)
+    credential_cache: Option<Arc<CredentialCache>>,
+    /// The static value of the `Authorization` header (used without a credential provider)
+    authorization: Option<String>,
+}
+
+impl PushClient {
+    /// Send the batch (retrying failed attempts according to the retry policy) and return the
+    /// error of the last attempt if it could not be delivered
+    async fn push(&self, batch: Batch) -> Result<(), FenrirError> {
+        let mut builder = self
+            .client
+            .post(self.push_url.clone())
+            .header("Content-Type", batch.content_type);
+        if let Some(content_encoding) = batch.content_encoding {
+            builder = builder.header("Content-Encoding", content_encoding);
+        }
+        if let Some(tenant) = &batch.tenant {
+            builder = builder.header("X-Scope-OrgID", tenant);
+        }
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        // the body shares the payload with the copy kept for the spool
+        builder = builder.body(Bytes::from_owner(SharedPayload(batch.payload)));
+
+        let mut attempt = 1;
+        let mut refreshed = false;
+        loop {
+            let retry = builder.try_clone().expect("should be able to clone");
+
+            // the credentials of a provider might change between two attempts
+            let authorization = match &self.credential_cache {
+                Some(credential_cache) => credential_cache.authorization_async().await.map(Some),
+                None => Ok(self.authorization.clone()),
+            };
+            let result = match authorization {
+                Ok(Some(authorization)) => {
+                    send_request(builder.header("Authorization", authorization)).await
+                }
+                Ok(None) => send_request(builder).await,
+                Err(error) => Err(error),
+            };
+            let error = match result {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+
+            // rejected credentials are refreshed once before giving up
+            if let (Some(credential_cache), Some(401), false) =
+                (&self.credential_cache, error.status(), refreshed)
+            {
+                credential_cache.invalidate();
+                refreshed = true;
+                builder = retry;
+                continue;
+            }
+
+            match self.retry_policy.next_delay(attempt, &error) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(error),
+            }
+            attempt += 1;
+            builder = retry;
+        }
+    }
+}
+
+/// Send the request and convert all errors (including HTTP status codes indicating an error) into
+/// the corresponding [`FenrirError`]
+async fn send_request(builder: RequestBuilder) -> Result<(), FenrirError> {
+    let response = builder
+        .send()
+        .await
+        .map_err(|error| match error.is_timeout() {
+            true => FenrirError::Timeout(Box::new(error)),
+            false => FenrirError::Transport(Box::new(error)),
+        })?;
+    if !response.status().is_success() {
+        let status = response.status().as_u16();
+        let retry_after = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        return Err(FenrirError::HttpStatus {
+            status,
+            body: response.text().await.unwrap_or_default(),
+            retry_after,
+        });
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::reqwest::ReqwestBackend;
     use crate::{AuthenticationMethod, Fenrir, NetworkingBackend, SerializationFormat};
+    use log::{Log, Record};
+    use parking_lot::Mutex;
     use std::any::{Any, TypeId};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
     use url::Url;
 
+    /// Start a local Loki stand-in which answers each request after the supplied `delay` with the
+    /// status code selected by `status` (based on the body of the request) and return its URL
+    /// together with the status codes of all requests answered so far
+    fn serve(delay: Duration, status: fn(&[u8]) -> u16) -> (Url, Arc<Mutex<Vec<u16>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let answered = Arc::new(Mutex::new(Vec::new()));
+        let statuses = answered.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    return;
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap_or(0);
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                let _ = reader.read_exact(&mut body);
+                std::thread::sleep(delay);
+                let status = status(&body);
+                statuses.lock().push(status);
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .as_bytes(),
+                );
+            }
+        });
+        (url, answered)
+    }
+
+    /// Create a new [`Fenrir`] instance which sends its log messages to the supplied `endpoint`
+    fn reqwest_fenrir(endpoint: Url) -> Fenrir {
+        Fenrir::builder()
+            .endpoint(endpoint)
+            .network(NetworkingBackend::Reqwest)
+            .format(SerializationFormat::Json)
+            .build()
+    }
+
     #[tokio::test]
     async fn creating_a_reqwest_instance_without_credentials_works_correctly() {
         let result = Fenrir::builder()
@@ -98,12 +360,12 @@ mod tests {
             .format(SerializationFormat::Json)
             .build();
         assert_eq!(
-            result.backend.authentication_method(),
+            result.shipper.backend.authentication_method(),
             AuthenticationMethod::None
         );
-        assert_eq!(result.backend.credentials(), None);
+        assert_eq!(result.shipper.backend.credentials(), None);
         assert_eq!(
-            result.backend.internal_type(),
+            result.shipper.backend.internal_type(),
             TypeId::of::<ReqwestBackend>().type_id()
         );
     }
@@ -121,16 +383,81 @@ mod tests {
             )
             .build();
         assert_eq!(
-            result.backend.authentication_method(),
+            result.shipper.backend.authentication_method(),
             AuthenticationMethod::Basic
         );
         assert_eq!(
-            result.backend.credentials(),
+            result.shipper.backend.credentials(),
             Some("dXNlcm5hbWU6cGFzc3dvcmQ=".to_string())
         );
         assert_eq!(
-            result.backend.internal_type(),
+            result.shipper.backend.internal_type(),
             TypeId::of::<ReqwestBackend>().type_id()
         );
     }
+
+    #[tokio::test]
+    async fn log_messages_of_reqwest_and_its_dependencies_are_excluded() {
+        use log::Level;
+
+        let fenrir = Fenrir::builder()
+            .network(NetworkingBackend::Reqwest)
+            .build();
+        let excluded = |target: &str| {
+            fenrir.is_excluded(&Record::builder().level(Level::Info).target(target).build())
+        };
+
+        assert!(excluded("reqwest::connect"));
+        assert!(excluded("hyper::proto::h1"));
+        assert!(excluded("hyper_util::client"));
+        assert!(excluded("h2::codec"));
+        assert!(!excluded("reqwest_middleware"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutting_down_waits_for_the_requests_in_flight() {
+        let (endpoint, answered) = serve(Duration::from_millis(200), |_| 204);
+        let fenrir = reqwest_fenrir(endpoint);
+        fenrir.log(&Record::builder().args(format_args!("message")).build());
+
+        let undelivered = tokio::task::block_in_place(|| fenrir.shutdown(Duration::from_secs(10)));
+        assert_eq!(undelivered, 0);
+        assert_eq!(*answered.lock(), vec![204]);
+    }
+
+    #[tokio::test]
+    async fn dropping_an_instance_within_a_single_threaded_runtime_does_not_wait() {
+        let (endpoint, _) = serve(Duration::ZERO, |_| 204);
+        let fenrir = reqwest_fenrir(endpoint);
+        fenrir.log(&Record::builder().args(format_args!("message")).build());
+
+        let started = Instant::now();
+        drop(fenrir);
+        assert!(started.elapsed() < crate::DEFAULT_SHUTDOWN_TIMEOUT);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batches_which_are_too_large_for_loki_are_split() {
+        // only requests containing a single log entry are accepted
+        let (endpoint, answered) = serve(Duration::ZERO, |body| {
+            match String::from_utf8_lossy(body).matches("entry-").count() {
+                1 => 204,
+                _ => 413,
+            }
+        });
+        let fenrir = reqwest_fenrir(endpoint);
+        for entry in 1..=4 {
+            fenrir.log(
+                &Record::builder()
+                    .args(format_args!("entry-{}", entry))
+                    .build(),
+            );
+        }
+
+        let undelivered = tokio::task::block_in_place(|| fenrir.shutdown(Duration::from_secs(10)));
+        assert_eq!(undelivered, 0);
+        let answered = answered.lock();
+        assert_eq!(answered.iter().filter(|status| **status == 204).count(), 4);
+        assert_eq!(answered[0], 413);
+    }
 }
@@ -0,0 +1,278 @@
+//! A module which contains the policy used by the network backends for retrying the delivery of
+//! log messages.
+use crate::FenrirError;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The [`RetryPolicy`] decides if and when the delivery of log messages to Loki is retried.
+///
+/// Failed requests are retried with an exponentially growing delay (starting at
+/// [`RetryPolicy::base_delay`] and capped at [`RetryPolicy::max_delay`]). If Loki answers with a
+/// `Retry-After` header, its value is used as the delay instead. If Loki asks to wait longer than
+/// [`RetryPolicy::max_delay`], the delivery is not retried at all (and the batch is stored in the
+/// spool, if one is configured). Client errors (`4xx`) except for `429 Too Many Requests` are
+/// treated as permanent and never retried.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts to deliver a batch (including the first one)
+    pub max_attempts: u32,
+    /// The delay before the first retry
+    pub base_delay: Duration,
+    /// The maximum delay between two attempts
+    pub max_delay: Duration,
+    /// If set to `true`, the computed delays are randomly reduced by up to 50% to avoid that many
+    /// clients retry at the same time
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a policy which never retries the delivery of log messages
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::RetryPolicy;
+    ///
+    /// let policy = RetryPolicy::none();
+    /// assert_eq!(policy.max_attempts, 1);
+    /// ```
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Get the delay before the next attempt after `attempt` (starting at 1) failed with `error`
+    /// or `None` if the delivery should not be retried.
+    ///
+    /// This can be used by custom implementations of the [`crate::FenrirBackend`] trait for
+    /// retrying requests the same way the built-in network backends do.
+    ///
+    /// # Example
+    /// ```
+    /// use fenrir_rs::{FenrirError, RetryPolicy};
+    ///
+    /// let error = FenrirError::HttpStatus { status: 400, body: String::new(), retry_after: None };
+    /// assert_eq!(RetryPolicy::default().next_delay(1, &error), None);
+    /// ```
+    pub fn next_delay(&self, attempt: u32, error: &FenrirError) -> Option<Duration> {
+        if attempt >= self.max_attempts || !is_retryable(error) {
+            return None;
+        }
+
+        if let FenrirError::HttpStatus {
+            retry_after: Some(retry_after),
+            ..
+        } = error
+        {
+            // retrying earlier than requested would most likely be rejected again
+            return Some(*retry_after).filter(|retry_after| *retry_after <= self.max_delay);
+        }
+
+        let factor = 2u32.saturating_pow(attempt - 1);
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        match self.jitter {
+            true => Some(delay.mul_f64(0.5 + random_fraction() / 2.0)),
+            false => Some(delay),
+        }
+    }
+}
+
+/// Check if the delivery of a batch which failed with `error` should be retried
+pub(crate) fn is_retryable(error: &FenrirError) -> bool {
+    match error {
+        FenrirError::HttpStatus { status: 429, .. } => true,
+        FenrirError::HttpStatus { status, .. } => !(400..500).contains(status),
+        FenrirError::Transport(_) | FenrirError::Timeout(_) => true,
+        FenrirError::Serialization(_)
+        | FenrirError::Configuration(_)
+        | FenrirError::Authentication(_)
+        | FenrirError::BufferFull
+        | FenrirError::Spool(_)
+        | FenrirError::SpoolFull
+        | FenrirError::LabelRejected { .. } => false,
+    }
+}
+
+/// Get a random number in the range `[0, 1)`
+fn random_fraction() -> f64 {
+    // every `RandomState` is seeded differently, which is random enough for jitter
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Parse the value of a `Retry-After` header, which is either a number of seconds or an HTTP date
+/// (e.g. `Wed, 21 Oct 2015 07:28:00 GMT`)
+#[cfg_attr(
+    not(any(feature = "ureq", feature = "reqwest-async")),
+    allow(dead_code) // only used by the network backends
+)]
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = parse_http_date(value)?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Parse a date in the preferred format of HTTP (`IMF-fixdate`, e.g. `Wed, 21 Oct 2015 07:28:00
+/// GMT`)
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (_, date) = value.split_once(", ")?;
+    let parts = date.split(' ').collect::<Vec<_>>();
+    if parts.len() != 5 || parts[4] != "GMT" {
+        return None;
+    }
+    let day = parts[0].parse::<u64>().ok()?;
+    let month = MONTHS.iter().position(|month| *month == parts[1])? as u64 + 1;
+    let year = parts[2].parse::<u64>().ok()?;
+    let time = parts[3]
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if year < 1970 || !(1..=31).contains(&day) || time.len() != 3 {
+        return None;
+    }
+
+    // count the days since the UNIX epoch (see http://howardhinnant.github.io/date_algorithms.html)
+    let (year, month) = match month > 2 {
+        true => (year, month - 3),
+        false => (year - 1, month + 9),
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146097 + day_of_era).checked_sub(719468)?;
+
+    let seconds = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retry::{parse_http_date, parse_retry_after, RetryPolicy};
+    use crate::FenrirError;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn status(status: u16, retry_after: Option<Duration>) -> FenrirError {
+        FenrirError::HttpStatus {
+            status,
+            body: String::new(),
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn the_delay_grows_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            jitter: false,
+        };
+        let error = status(503, None);
+
+        assert_eq!(policy.next_delay(1, &error), Some(Duration::from_secs(1)));
+        assert_eq!(policy.next_delay(2, &error), Some(Duration::from_secs(2)));
+        assert_eq!(policy.next_delay(3, &error), Some(Duration::from_secs(4)));
+        assert_eq!(policy.next_delay(4, &error), Some(Duration::from_secs(5)));
+        assert_eq!(policy.next_delay(9, &error), Some(Duration::from_secs(5)));
+        assert_eq!(policy.next_delay(10, &error), None);
+    }
+
+    #[test]
+    fn the_jitter_reduces_the_delay_by_up_to_half() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(2),
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.next_delay(1, &status(500, None)).unwrap();
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn client_errors_except_too_many_requests_are_not_retried() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.next_delay(1, &status(400, None)), None);
+        assert_eq!(policy.next_delay(1, &status(401, None)), None);
+        assert_eq!(policy.next_delay(1, &status(413, None)), None);
+        assert!(policy.next_delay(1, &status(429, None)).is_some());
+        assert!(policy.next_delay(1, &status(502, None)).is_some());
+        assert_eq!(
+            policy.next_delay(1, &FenrirError::Configuration(String::new())),
+            None
+        );
+        assert_eq!(RetryPolicy::none().next_delay(1, &status(503, None)), None);
+    }
+
+    #[test]
+    fn the_retry_after_header_is_honored_up_to_the_maximum_delay() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(
+            policy.next_delay(1, &status(429, Some(Duration::from_secs(7)))),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            policy.next_delay(1, &status(429, Some(policy.max_delay))),
+            Some(policy.max_delay)
+        );
+        assert_eq!(
+            policy.next_delay(1, &status(503, Some(Duration::from_secs(3600)))),
+            None
+        );
+    }
+
+    #[test]
+    fn retry_after_values_can_be_parsed() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_445_412_480))
+        );
+        assert_eq!(
+            parse_http_date("Sun, 29 Feb 2004 23:59:59 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_078_099_199))
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("Wed, 21 Foo 2015 07:28:00 GMT"), None);
+    }
+}
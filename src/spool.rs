@@ -0,0 +1,704 @@
+//! A module which contains the spool, which keeps batches which could not be delivered to Loki on
+//! disk until the endpoint is reachable again.
+use crate::error::ErrorHandler;
+use crate::retry::is_retryable;
+use crate::{Batch, DroppedBatchInfo, FenrirBackend, FenrirError};
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The bytes each segment starts with
+const MAGIC: &[u8; 4] = b"FNRS";
+
+/// The version of the format of the segments
+const VERSION: u8 = 1;
+
+/// The extension of the segments which were written completely
+const SEGMENT_EXTENSION: &str = "segment";
+
+/// The extension of the segments which are still being written
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// The maximum time the replay waits for a batch sent in the background to be delivered
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The interval in which the replay checks if it should stop while waiting for a delivery
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The values the content headers of a [`Batch`] can have
+const CONTENT_HEADERS: [&str; 5] = [
+    "application/octet-stream",
+    "application/json; charset=utf-8",
+    "application/x-protobuf",
+    "snappy",
+    "gzip",
+];
+
+/// A [`Segment`] is a file in the spool directory which contains a single [`Batch`].
+#[derive(Clone, Debug, PartialEq)]
+struct Segment {
+    /// The position of the segment in the spool (segments are replayed in ascending order)
+    sequence: u64,
+    /// The number of log entries in the batch
+    entries: usize,
+    /// The size of the file in bytes
+    bytes: usize,
+}
+
+impl Segment {
+    /// Get the name of the file which contains the segment
+    fn file_name(&self) -> String {
+        format!(
+            "{:020}-{}.{}",
+            self.sequence, self.entries, SEGMENT_EXTENSION
+        )
+    }
+
+    /// Parse the name of a segment file (returns `None` for all other files)
+    fn parse(file_name: &str, bytes: usize) -> Option<Segment> {
+        let name = file_name
+            .strip_suffix(SEGMENT_EXTENSION)?
+            .strip_suffix('.')?;
+        let (sequence, entries) = name.split_once('-')?;
+        Some(Segment {
+            sequence: sequence.parse().ok()?,
+            entries: entries.parse().ok()?,
+            bytes,
+        })
+    }
+}
+
+/// The state of the delivery of a replayed [`Batch`]
+#[derive(Debug)]
+enum DeliveryState {
+    /// The backend did not claim the receipt, so the batch was delivered synchronously
+    Unclaimed,
+    /// The batch is still being sent in the background
+    Pending,
+    /// The batch was sent in the background with the contained result
+    Done(Result<(), FenrirError>),
+}
+
+/// The [`DeliveryReceipt`] is attached to replayed batches, so backends which send batches in the
+/// background (like the `Reqwest` backend) can report the result of the delivery back to the
+/// replay. The segment of a batch is only removed from the spool once it was delivered.
+#[derive(Clone, Debug)]
+pub(crate) struct DeliveryReceipt {
+    /// The state of the delivery and the condition variable notified when it is done
+    inner: Arc<(Mutex<DeliveryState>, Condvar)>,
+}
+
+impl DeliveryReceipt {
+    /// Create a new receipt which was not claimed by a backend yet
+    fn new() -> DeliveryReceipt {
+        DeliveryReceipt {
+            inner: Arc::new((Mutex::new(DeliveryState::Unclaimed), Condvar::new())),
+        }
+    }
+
+    /// Tell the replay that the batch is sent in the background and the result is reported
+    /// using [`DeliveryReceipt::complete`] later on
+    #[cfg_attr(not(feature = "reqwest-async"), allow(dead_code))]
+    pub(crate) fn claim(&self) {
+        *self.inner.0.lock() = DeliveryState::Pending;
+    }
+
+    /// Report the result of a delivery which was sent in the background
+    #[cfg_attr(not(feature = "reqwest-async"), allow(dead_code))]
+    pub(crate) fn complete(&self, result: Result<(), FenrirError>) {
+        *self.inner.0.lock() = DeliveryState::Done(result);
+        self.inner.1.notify_all();
+    }
+
+    /// Wait up to `timeout` for the result of the delivery. Returns `None` if the batch is still
+    /// being sent afterward or `stopped` returned `true` in the meantime.
+    fn wait(
+        &self,
+        timeout: Duration,
+        stopped: &impl Fn() -> bool,
+    ) -> Option<Result<(), FenrirError>> {
+        let deadline = Instant::now() + timeout;
+        let (state, delivered) = &*self.inner;
+        let mut state = state.lock();
+        loop {
+            match std::mem::replace(&mut *state, DeliveryState::Pending) {
+                DeliveryState::Unclaimed => return Some(Ok(())),
+                DeliveryState::Done(result) => return Some(result),
+                DeliveryState::Pending => {}
+            }
+            let now = Instant::now();
+            if now >= deadline || stopped() {
+                return None;
+            }
+            delivered.wait_for(&mut state, STOP_CHECK_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+/// The segments which are currently stored in the spool
+struct SpoolState {
+    /// The segments ordered by their sequence number
+    segments: VecDeque<Segment>,
+    /// The sequence number of the next segment
+    next_sequence: u64,
+    /// The size of all segments in bytes
+    bytes: usize,
+}
+
+/// The [`Spool`] stores batches which could not be delivered to Loki as segment files in a
+/// directory, so they can be sent again as soon as Loki is reachable (even after a restart of the
+/// application).
+///
+/// Each segment is protected by a checksum. Segments which are corrupted are dropped when they are
+/// replayed and segments which were not written completely are removed when the spool is opened.
+pub(crate) struct Spool {
+    /// The directory which contains the segments
+    dir: PathBuf,
+    /// The maximum size of all segments in bytes
+    max_bytes: usize,
+    /// The segments which are currently stored
+    state: Mutex<SpoolState>,
+    /// The number of log entries which were dropped by the spool
+    pub(crate) dropped: AtomicUsize,
+    /// The handler which is informed about log entries which were dropped by the spool
+    error_handler: Arc<ErrorHandler>,
+}
+
+impl Spool {
+    /// Open the spool in the supplied directory (which is created if it does not exist yet)
+    pub(crate) fn open(
+        dir: PathBuf,
+        max_bytes: usize,
+        error_handler: Arc<ErrorHandler>,
+    ) -> std::io::Result<Spool> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut segments = Vec::new();
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == TEMPORARY_EXTENSION)
+            {
+                // the application stopped while the segment was written
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            let bytes = std::fs::metadata(&path)?.len() as usize;
+            if let Some(segment) = path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .and_then(|file_name| Segment::parse(file_name, bytes))
+            {
+                segments.push(segment);
+            }
+        }
+        segments.sort_by_key(|segment| segment.sequence);
+
+        Ok(Spool {
+            dir,
+            max_bytes,
+            state: Mutex::new(SpoolState {
+                next_sequence: segments.last().map_or(0, |segment| segment.sequence + 1),
+                bytes: segments.iter().map(|segment| segment.bytes).sum(),
+                segments: segments.into(),
+            }),
+            dropped: AtomicUsize::new(0),
+            error_handler,
+        })
+    }
+
+    /// Store a batch which could not be delivered as a new segment. If the spool would exceed its
+    /// maximum size, the oldest segments are dropped.
+    pub(crate) fn store(&self, batch: &Batch) -> Result<(), FenrirError> {
+        let data = encode(batch);
+        if data.len() > self.max_bytes {
+            return Err(FenrirError::Spool(std::io::Error::other(
+                "the batch is larger than the spool",
+            )));
+        }
+
+        let mut state = self.state.lock();
+        let mut dropped = DroppedBatchInfo {
+            entries: 0,
+            bytes: 0,
+        };
+        while state.bytes + data.len() > self.max_bytes {
+            let Some(segment) = state.segments.pop_front() else {
+                break;
+            };
+            state.bytes -= segment.bytes;
+            dropped.entries += segment.entries;
+            dropped.bytes += segment.bytes;
+            let _ = remove_file(&self.dir.join(segment.file_name()));
+        }
+
+        let segment = Segment {
+            sequence: state.next_sequence,
+            entries: batch.entries,
+            bytes: data.len(),
+        };
+        let result = self.write(&segment, &data);
+        if result.is_ok() {
+            state.next_sequence += 1;
+            state.bytes += segment.bytes;
+            state.segments.push_back(segment);
+        }
+        drop(state);
+
+        // the error handler must not be called while holding the lock on the segments
+        if dropped.entries > 0 {
+            self.dropped.fetch_add(dropped.entries, Ordering::Relaxed);
+            self.error_handler.report(&FenrirError::SpoolFull, dropped);
+        }
+        result.map_err(FenrirError::Spool)
+    }
+
+    /// Send the spooled batches to the `backend` (oldest first) until the spool is empty, a batch
+    /// could not be delivered because of a temporary error or `stopped` returns `true`.
+    ///
+    /// Batches which are corrupted or which are rejected by Loki are dropped. A segment is only
+    /// removed once its batch was delivered. For backends which send the batches in the
+    /// background, the replay waits (up to [`DELIVERY_TIMEOUT`]) for the result of the delivery
+    /// and stops if it is not known in time, so the segment is replayed again later on.
+    pub(crate) fn replay(&self, backend: &dyn FenrirBackend, stopped: impl Fn() -> bool) {
+        while !stopped() {
+            let Some(segment) = self.state.lock().segments.front().cloned() else {
+                return;
+            };
+            let batch = match std::fs::read(self.dir.join(segment.file_name())) {
+                Ok(data) => decode(&data, segment.entries).map_err(FenrirError::Spool),
+                // the segment was dropped while it was read since the spool was full
+                Err(error) if error.kind() == ErrorKind::NotFound => {
+                    self.remove(&segment);
+                    continue;
+                }
+                // the segment might be readable later on
+                Err(_) => return,
+            };
+
+            let result = batch.and_then(|mut batch| {
+                let receipt = DeliveryReceipt::new();
+                batch.receipt = Some(receipt.clone());
+                backend.send(batch)?;
+                receipt.wait(DELIVERY_TIMEOUT, &stopped).unwrap_or_else(|| {
+                    Err(FenrirError::Timeout(
+                        "the replayed batch was not delivered in time".into(),
+                    ))
+                })
+            });
+            let error = match result {
+                Ok(()) => {
+                    self.remove(&segment);
+                    continue;
+                }
+                Err(error) if is_retryable(&error) => return,
+                Err(error) => error,
+            };
+            self.remove(&segment);
+            self.dropped.fetch_add(segment.entries, Ordering::Relaxed);
+            self.error_handler.report(
+                &error,
+                DroppedBatchInfo {
+                    entries: segment.entries,
+                    bytes: segment.bytes,
+                },
+            );
+        }
+    }
+
+    /// Write the segment to a temporary file first and rename it afterward, so segments which
+    /// were not written completely are never replayed
+    fn write(&self, segment: &Segment, data: &[u8]) -> std::io::Result<()> {
+        let path = self.dir.join(segment.file_name());
+        let temporary_path = path.with_extension(TEMPORARY_EXTENSION);
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary_path, &path)
+    }
+
+    /// Remove a segment from the spool (if it was not dropped already)
+    fn remove(&self, segment: &Segment) {
+        let mut state = self.state.lock();
+        if let Some(position) = state
+            .segments
+            .iter()
+            .position(|stored| stored.sequence == segment.sequence)
+        {
+            state.segments.remove(position);
+            state.bytes -= segment.bytes;
+            let _ = remove_file(&self.dir.join(segment.file_name()));
+        }
+    }
+}
+
+/// Remove a file and ignore the error if it did not exist anymore
+fn remove_file(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Encode a batch as the content of a segment
+fn encode(batch: &Batch) -> Vec<u8> {
+    let mut data = Vec::with_capacity(batch.payload.len() + 128);
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    write_string(&mut data, Some(batch.content_type));
+    write_string(&mut data, batch.content_encoding);
+    write_string(&mut data, batch.tenant.as_deref());
+    data.extend_from_slice(&(batch.payload.len() as u64).to_le_bytes());
+    data.extend_from_slice(&batch.payload);
+    data.extend_from_slice(&crc32(&data).to_le_bytes());
+    data
+}
+
+/// Append an optional string (prefixed by a flag and its length) to the data of a segment
+fn write_string(data: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            data.push(1);
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value.as_bytes());
+        }
+        None => data.push(0),
+    }
+}
+
+/// Decode the content of a segment which contains `entries` log entries
+fn decode(data: &[u8], entries: usize) -> std::io::Result<Batch> {
+    let corrupted = || std::io::Error::new(ErrorKind::InvalidData, "the segment is corrupted");
+
+    if data.len() < 4 {
+        return Err(corrupted());
+    }
+    let (content, checksum) = data.split_at(data.len() - 4);
+    if crc32(content).to_le_bytes() != checksum {
+        return Err(corrupted());
+    }
+    let mut reader = Reader(content);
+    if reader.take(MAGIC.len())? != MAGIC || reader.take(1)? != [VERSION] {
+        return Err(corrupted());
+    }
+    let content_type = reader
+        .string()?
+        .and_then(content_header)
+        .ok_or_else(corrupted)?;
+    let content_encoding = match reader.string()? {
+        Some(content_encoding) => Some(content_header(content_encoding).ok_or_else(corrupted)?),
+        None => None,
+    };
+    let tenant = reader.string()?.map(str::to_string);
+    let length = u64::from_le_bytes(reader.take(8)?.try_into().unwrap_or_default());
+    let payload = reader.take(length as usize)?.to_vec();
+
+    Ok(Batch {
+        payload: Arc::new(payload),
+        content_type,
+        content_encoding,
+        entries,
+        tenant,
+        streams: None,
+        receipt: None,
+    })
+}
+
+/// Get the static value of a content header read from a segment
+fn content_header(value: &str) -> Option<&'static str> {
+    CONTENT_HEADERS
+        .iter()
+        .find(|header| **header == value)
+        .copied()
+}
+
+/// A simple reader for the content of a segment
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// Read the next `length` bytes
+    fn take(&mut self, length: usize) -> std::io::Result<&'a [u8]> {
+        if self.0.len() < length {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the segment is truncated",
+            ));
+        }
+        let (value, remaining) = self.0.split_at(length);
+        self.0 = remaining;
+        Ok(value)
+    }
+
+    /// Read an optional string written by [`write_string`]
+    fn string(&mut self) -> std::io::Result<Option<&'a str>> {
+        if self.take(1)? == [0] {
+            return Ok(None);
+        }
+        let length = u32::from_le_bytes(self.take(4)?.try_into().unwrap_or_default());
+        std::str::from_utf8(self.take(length as usize)?)
+            .map(Some)
+            .map_err(|error| std::io::Error::new(ErrorKind::InvalidData, error))
+    }
+}
+
+/// Calculate the CRC-32 checksum (as used by zlib) of the supplied data
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorHandler;
+    use crate::spool::{crc32, encode, Spool};
+    use crate::{Batch, FenrirBackend, FenrirError};
+    use parking_lot::Mutex;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A backend which fails with `503 Service Unavailable` until it is available and records all
+    /// batches it delivered
+    #[derive(Default)]
+    struct OutageBackend {
+        available: AtomicBool,
+        delivered: Mutex<Vec<Batch>>,
+    }
+
+    impl FenrirBackend for OutageBackend {
+        fn send(&self, batch: Batch) -> Result<(), FenrirError> {
+            if !self.available.load(Ordering::SeqCst) {
+                return Err(FenrirError::HttpStatus {
+                    status: 503,
+                    body: String::new(),
+                    retry_after: None,
+                });
+            }
+            self.delivered.lock().push(batch);
+            Ok(())
+        }
+    }
+
+    /// A backend which sends the batches on a separate thread (like the `Reqwest` backend) and
+    /// answers with the supplied HTTP status code (or never, if no status code is supplied)
+    struct BackgroundBackend {
+        status: Option<u16>,
+        delivered: Arc<Mutex<Vec<Batch>>>,
+    }
+
+    impl BackgroundBackend {
+        fn new(status: Option<u16>) -> BackgroundBackend {
+            BackgroundBackend {
+                status,
+                delivered: Default::default(),
+            }
+        }
+    }
+
+    impl FenrirBackend for BackgroundBackend {
+        fn send(&self, mut batch: Batch) -> Result<(), FenrirError> {
+            let receipt = batch.receipt.take().unwrap();
+            receipt.claim();
+            let status = self.status;
+            let delivered = self.delivered.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                match status {
+                    Some(204) => {
+                        delivered.lock().push(batch);
+                        receipt.complete(Ok(()));
+                    }
+                    Some(status) => receipt.complete(Err(FenrirError::HttpStatus {
+                        status,
+                        body: String::new(),
+                        retry_after: None,
+                    })),
+                    None => {}
+                }
+            });
+            Ok(())
+        }
+    }
+
+    /// Get the sequence numbers of all segments stored in the spool
+    fn sequences(spool: &Spool) -> Vec<u64> {
+        let state = spool.state.lock();
+        state
+            .segments
+            .iter()
+            .map(|segment| segment.sequence)
+            .collect()
+    }
+
+    /// Get an empty directory for a spool used by a single test
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("fenrir-spool-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Create a batch with the supplied payload
+    fn batch(payload: &str, entries: usize, tenant: Option<&str>) -> Batch {
+        Batch {
+            payload: Arc::new(payload.as_bytes().to_vec()),
+            content_type: "application/json; charset=utf-8",
+            content_encoding: Some("gzip"),
+            entries,
+            tenant: tenant.map(str::to_string),
+            streams: None,
+            receipt: None,
+        }
+    }
+
+    /// Get the payloads of all batches delivered by the backend
+    fn payloads(backend: &OutageBackend) -> Vec<String> {
+        backend
+            .delivered
+            .lock()
+            .iter()
+            .map(|batch| String::from_utf8(batch.payload.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn the_checksum_matches_the_one_of_zlib() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn spooled_batches_are_replayed_in_order_after_a_restart() {
+        let dir = spool_dir("restart");
+        let spool = Spool::open(dir.clone(), 1024, Default::default()).unwrap();
+        spool.store(&batch("first", 1, Some("tenant"))).unwrap();
+        spool.store(&batch("second", 2, None)).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(dir.clone(), 1024, Default::default()).unwrap();
+        let backend = OutageBackend::default();
+        spool.replay(&backend, || false);
+        assert!(payloads(&backend).is_empty());
+
+        backend.available.store(true, Ordering::SeqCst);
+        spool.replay(&backend, || false);
+        assert_eq!(payloads(&backend), vec!["first", "second"]);
+        let delivered = backend.delivered.lock();
+        assert_eq!(delivered[0].tenant(), Some("tenant"));
+        assert_eq!(delivered[0].content_encoding(), Some("gzip"));
+        assert_eq!(delivered[1].entries(), 2);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // new segments never reuse the sequence numbers of earlier ones
+        spool.store(&batch("third", 1, None)).unwrap();
+        assert_eq!(spool.state.lock().segments[0].sequence, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_oldest_segments_are_dropped_if_the_spool_is_full() {
+        let dir = spool_dir("full");
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let callback_dropped = dropped.clone();
+        let error_handler = Arc::new(ErrorHandler {
+            callback: Some(Box::new(move |error, info| {
+                callback_dropped
+                    .lock()
+                    .push((error.to_string(), info.entries));
+            })),
+            panic_on_error: false,
+        });
+        let size = encode(&batch("first", 1, None)).len();
+        let spool = Spool::open(dir.clone(), 2 * size, error_handler).unwrap();
+
+        spool.store(&batch("first", 1, None)).unwrap();
+        spool.store(&batch("secon", 2, None)).unwrap();
+        spool.store(&batch("third", 3, None)).unwrap();
+        assert!(spool.store(&batch(&"x".repeat(2 * size), 1, None)).is_err());
+
+        assert_eq!(spool.dropped.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            *dropped.lock(),
+            vec![(
+                "The spool for undelivered log messages is full".to_string(),
+                1
+            )]
+        );
+        let backend = OutageBackend::default();
+        backend.available.store(true, Ordering::SeqCst);
+        spool.replay(&backend, || false);
+        assert_eq!(payloads(&backend), vec!["secon", "third"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_and_incomplete_segments_are_dropped() {
+        let dir = spool_dir("corrupted");
+        let spool = Spool::open(dir.clone(), 1024, Default::default()).unwrap();
+        spool.store(&batch("first", 1, None)).unwrap();
+        spool.store(&batch("second", 1, None)).unwrap();
+        std::fs::write(dir.join("00000000000000000002-1.tmp"), b"incomplete").unwrap();
+        drop(spool);
+
+        // flip a single bit of the payload of the first segment
+        let path = dir.join("00000000000000000000-1.segment");
+        let mut data = std::fs::read(&path).unwrap();
+        let position = data.len() - 6;
+        data[position] ^= 1;
+        std::fs::write(&path, data).unwrap();
+
+        let spool = Spool::open(dir.clone(), 1024, Default::default()).unwrap();
+        assert!(!dir.join("00000000000000000002-1.tmp").exists());
+        let backend = OutageBackend::default();
+        backend.available.store(true, Ordering::SeqCst);
+        spool.replay(&backend, || false);
+
+        assert_eq!(payloads(&backend), vec!["second"]);
+        assert_eq!(spool.dropped.load(Ordering::SeqCst), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segments_are_kept_until_batches_sent_in_the_background_were_delivered() {
+        let dir = spool_dir("background");
+        let spool = Spool::open(dir.clone(), 1024, Default::default()).unwrap();
+        spool.store(&batch("first", 1, None)).unwrap();
+        spool.store(&batch("second", 1, None)).unwrap();
+
+        // a temporary error stops the replay without changing the order of the segments
+        spool.replay(&BackgroundBackend::new(Some(503)), || false);
+        assert_eq!(sequences(&spool), vec![0, 1]);
+
+        // the replay does not wait for the delivery anymore as soon as it should stop
+        let checks = AtomicUsize::new(0);
+        spool.replay(&BackgroundBackend::new(None), || {
+            checks.fetch_add(1, Ordering::SeqCst) > 0
+        });
+        assert_eq!(sequences(&spool), vec![0, 1]);
+
+        let backend = BackgroundBackend::new(Some(204));
+        spool.replay(&backend, || false);
+        let payloads = backend
+            .delivered
+            .lock()
+            .iter()
+            .map(|batch| String::from_utf8(batch.payload.to_vec()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, vec!["first", "second"]);
+        assert!(sequences(&spool).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
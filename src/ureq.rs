@@ -1,51 +1,99 @@
 //! A module which contains the implementation for the [`FenrirBackend`] trait which uses the `ureq`
 //! crate for network communication.
-use crate::{AuthenticationMethod, FenrirBackend};
+use crate::credentials::CredentialCache;
+use crate::retry::parse_retry_after;
+use crate::{AuthenticationMethod, Batch, FenrirBackend, FenrirError, RetryPolicy};
 use std::any::TypeId;
+use std::sync::Arc;
+use std::time::Duration;
 use ureq::Agent;
 use url::Url;
 
 /// A [`FenrirBackend`] implementation which uses the [ureq](https://crates.io/crates/ureq) crate to
 /// send logging messages to a Loki endpoint.
 pub(crate) struct UreqBackend {
-    /// The loki endpoint which is used to send log information to
-    pub(crate) endpoint: Url,
-    /// The authentication method to use when sending the log messages to the remote [`UreqBackend::endpoint`]
+    /// The URL of the push API of the Loki endpoint which is used to send log information to
+    pub(crate) push_url: Url,
+    /// The authentication method to use when sending the log messages to the remote [`UreqBackend::push_url`]
     pub(crate) authentication: AuthenticationMethod,
-    /// The credentials to use to authenticate against the remote [`UreqBackend::endpoint`]
+    /// The credentials to use to authenticate against the remote [`UreqBackend::push_url`]
     pub(crate) credentials: String,
+    /// The agent which is used for all requests, so connections to the remote
+    /// [`UreqBackend::push_url`] can be reused
+    pub(crate) agent: Agent,
+    /// The policy which decides if and when failed requests are retried
+    pub(crate) retry_policy: RetryPolicy,
+    /// Additional HTTP headers which are sent with each request
+    pub(crate) headers: Vec<(String, String)>,
+    /// The cache for the credentials of the configured provider (if any)
+    pub(crate) credential_cache: Option<Arc<CredentialCache>>,
+}
+
+impl UreqBackend {
+    /// Send the batch to the push API once and convert all errors (including HTTP status
+    /// codes indicating an error) into the corresponding [`FenrirError`]
+    fn send_once(&self, batch: &Batch) -> Result<(), FenrirError> {
+        let mut request = self.agent.post(self.push_url.as_str());
+        request = request.header("Content-Type", batch.content_type);
+        if let Some(content_encoding) = batch.content_encoding {
+            request = request.header("Content-Encoding", content_encoding);
+        }
+        if let Some(tenant) = &batch.tenant {
+            request = request.header("X-Scope-OrgID", tenant);
+        }
+        let authorization = match &self.credential_cache {
+            Some(credential_cache) => Some(credential_cache.authorization()?),
+            None => self.authentication.authorization(&self.credentials),
+        };
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+
+        let mut response = request.send(batch.payload()).map_err(map_error)?;
+        if !response.status().is_success() {
+            let retry_after = response
+                .headers()
+                .get("Retry-After")
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            return Err(FenrirError::HttpStatus {
+                status: response.status().as_u16(),
+                body: response.body_mut().read_to_string().unwrap_or_default(),
+                retry_after,
+            });
+        }
+        Ok(())
+    }
 }
 
 impl FenrirBackend for UreqBackend {
-    fn send(&self, serialized_streams: Vec<u8>) -> Result<(), String> {
-        use std::time::Duration;
+    fn send(&self, batch: Batch) -> Result<(), FenrirError> {
+        let mut attempt = 1;
+        let mut refreshed = false;
+        loop {
+            let error = match self.send_once(&batch) {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
 
-        let agent_config = Agent::config_builder()
-            .timeout_global(Some(Duration::from_secs(10)))
-            .build();
+            // rejected credentials are refreshed once before giving up
+            if let (Some(credential_cache), Some(401), false) =
+                (&self.credential_cache, error.status(), refreshed)
+            {
+                credential_cache.invalidate();
+                refreshed = true;
+                continue;
+            }
 
-        let post_url = self
-            .endpoint
-            .clone()
-            .join("/loki/api/v1/push")
-            .map_err(|e| e.to_string())?;
-        let agent = agent_config.new_agent();
-        let mut request = agent.post(post_url.as_str());
-        request = request.header("Content-Type", "application/json; charset=utf-8");
-        match self.authentication {
-            AuthenticationMethod::None => {}
-            AuthenticationMethod::Basic => {
-                request = request.header(
-                    "Authorization",
-                    format!("Basic {}", self.credentials).as_str(),
-                );
+            match self.retry_policy.next_delay(attempt, &error) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(error),
             }
+            attempt += 1;
         }
-
-        request
-            .send(&serialized_streams)
-            .map_err(|e| e.to_string())?;
-        Ok(())
     }
 
     fn internal_type(&self) -> TypeId {
@@ -59,18 +107,52 @@ impl FenrirBackend for UreqBackend {
     }
 
     fn credentials(&self) -> Option<String> {
-        if self.credentials.len() > 0 {
+        if !self.credentials.is_empty() {
             return Some(self.credentials.clone());
         }
         None
     }
+
+    fn excluded_targets(&self) -> &'static [&'static str] {
+        &["ureq", "ureq_proto", "rustls"]
+    }
+}
+
+/// Create the [`Agent`] which is used for all requests of a [`UreqBackend`]
+pub(crate) fn new_agent(
+    timeout: Duration,
+    pool_size: usize,
+    idle_connection_timeout: Duration,
+) -> Agent {
+    Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .max_idle_connections(pool_size)
+        .max_idle_connections_per_host(pool_size)
+        .max_idle_age(idle_connection_timeout)
+        .http_status_as_error(false)
+        .build()
+        .new_agent()
+}
+
+/// Convert an error of the `ureq` crate into the corresponding [`FenrirError`]
+fn map_error(error: ureq::Error) -> FenrirError {
+    match error {
+        ureq::Error::StatusCode(status) => FenrirError::HttpStatus {
+            status,
+            body: String::new(),
+            retry_after: None,
+        },
+        ureq::Error::Timeout(_) => FenrirError::Timeout(Box::new(error)),
+        error => FenrirError::Transport(Box::new(error)),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ureq::UreqBackend;
+    use crate::ureq::{new_agent, UreqBackend};
     use crate::{AuthenticationMethod, Fenrir, NetworkingBackend, SerializationFormat};
     use std::any::{Any, TypeId};
+    use std::time::Duration;
     use url::Url;
 
     #[test]
@@ -81,12 +163,12 @@ mod tests {
             .format(SerializationFormat::Json)
             .build();
         assert_eq!(
-            result.backend.authentication_method(),
+            result.shipper.backend.authentication_method(),
             AuthenticationMethod::None
         );
-        assert_eq!(result.backend.credentials(), None);
+        assert_eq!(result.shipper.backend.credentials(), None);
         assert_eq!(
-            result.backend.internal_type(),
+            result.shipper.backend.internal_type(),
             TypeId::of::<UreqBackend>().type_id()
         );
     }
@@ -104,16 +186,67 @@ mod tests {
             )
             .build();
         assert_eq!(
-            result.backend.authentication_method(),
+            result.shipper.backend.authentication_method(),
             AuthenticationMethod::Basic
         );
         assert_eq!(
-            result.backend.credentials(),
+            result.shipper.backend.credentials(),
             Some("dXNlcm5hbWU6cGFzc3dvcmQ=".to_string())
         );
         assert_eq!(
-            result.backend.internal_type(),
+            result.shipper.backend.internal_type(),
             TypeId::of::<UreqBackend>().type_id()
         );
     }
+
+    #[test]
+    fn creating_a_ureq_instance_with_a_bearer_token_works_correctly() {
+        let result = Fenrir::builder()
+            .endpoint(Url::parse("https://loki.example.com").unwrap())
+            .network(NetworkingBackend::Ureq)
+            .format(SerializationFormat::Json)
+            .bearer_token("token")
+            .build();
+        assert_eq!(
+            result.shipper.backend.authentication_method(),
+            AuthenticationMethod::Bearer("token".to_string())
+        );
+        assert_eq!(
+            result.shipper.backend.credentials(),
+            Some("token".to_string())
+        );
+    }
+
+    #[test]
+    fn log_messages_of_ureq_and_its_dependencies_are_excluded() {
+        use log::{Level, Record};
+
+        let fenrir = Fenrir::builder()
+            .network(NetworkingBackend::Ureq)
+            .exclude_target("my_http_client")
+            .allow_target("rustls")
+            .build();
+        let excluded = |target: &str| {
+            fenrir.is_excluded(&Record::builder().level(Level::Info).target(target).build())
+        };
+
+        assert!(excluded("ureq"));
+        assert!(excluded("ureq::unversioned::transport"));
+        assert!(excluded("ureq_proto"));
+        assert!(excluded("my_http_client::pool"));
+        assert!(!excluded("ureq_helpers"));
+        assert!(!excluded("rustls"));
+    }
+
+    #[test]
+    fn the_agent_is_configured_with_the_supplied_connection_settings() {
+        let agent = new_agent(Duration::from_secs(30), 8, Duration::from_secs(60));
+        let config = agent.config();
+
+        assert_eq!(config.timeouts().global, Some(Duration::from_secs(30)));
+        assert_eq!(config.max_idle_connections(), 8);
+        assert_eq!(config.max_idle_connections_per_host(), 8);
+        assert_eq!(config.max_idle_age(), Duration::from_secs(60));
+        assert!(!config.http_status_as_error());
+    }
 }
@@ -0,0 +1,280 @@
+//! A module which contains the background worker used for flushing the buffered log entries
+//! periodically.
+use crate::LogShipper;
+use parking_lot::{Condvar, Mutex};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The interval in which the worker thread tries to replay the batches stored in the spool
+const REPLAY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The state of a worker thread
+#[derive(Default)]
+struct WorkerState {
+    /// Set to `true` as soon as the worker should stop
+    stopped: bool,
+    /// Set to `true` if the worker should flush the buffer as soon as possible
+    flush_requested: bool,
+    /// Set to `true` if the worker stopped since flushing the buffer panicked
+    panicked: bool,
+}
+
+/// The signal which is used to tell a worker thread that it should flush the buffer or stop.
+#[derive(Default)]
+pub(crate) struct WorkerSignal {
+    /// The current state of the worker
+    state: Mutex<WorkerState>,
+    /// Used to wake up the worker thread while it is waiting for the next flush
+    condvar: Condvar,
+}
+
+impl WorkerSignal {
+    /// Ask the worker thread to flush the buffer. Returns `false` if the worker was already
+    /// stopped, so the caller has to flush the buffer on its own.
+    pub(crate) fn request_flush(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.stopped {
+            return false;
+        }
+        state.flush_requested = true;
+        self.condvar.notify_all();
+        true
+    }
+
+    /// Check if the worker stopped since flushing the buffer panicked
+    pub(crate) fn has_panicked(&self) -> bool {
+        self.state.lock().panicked
+    }
+
+    /// Tell the worker thread to stop
+    fn stop(&self) {
+        self.state.lock().stopped = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// Flush the buffer of the `shipper` on its worker. If this panics (e.g. because of
+/// [`crate::FenrirBuilder::panic_on_error`]), the worker is marked as stopped before the panic is
+/// resumed, so the logging threads flush the buffer on their own instead of waiting for a worker
+/// which does not exist anymore.
+fn flush_on_worker(shipper: &LogShipper) {
+    if let Err(payload) = std::panic::catch_unwind(AssertUnwindSafe(|| shipper.flush())) {
+        shipper.worker_signal.state.lock().panicked = true;
+        shipper.worker_signal.stop();
+        std::panic::resume_unwind(payload);
+    }
+}
+
+/// The [`FlushWorker`] flushes the buffer of a [`LogShipper`] as soon as its oldest entry is older
+/// than the configured interval. A worker running on a separate thread additionally flushes the
+/// buffer whenever it is asked to using the [`LogShipper::worker_signal`].
+///
+/// A worker can also be used for replaying the batches stored in the spool of a [`LogShipper`]
+/// periodically (see [`FlushWorker::spawn_replay_thread`]).
+///
+/// The worker is stopped when it gets dropped.
+pub(crate) enum FlushWorker {
+    /// A worker running on a separate thread
+    Thread {
+        /// The signal used to stop the worker thread
+        signal: Arc<WorkerSignal>,
+        /// The handle used to wait for the worker thread to finish
+        handle: Option<JoinHandle<()>>,
+    },
+
+    /// A worker running as a task on a tokio runtime
+    #[cfg(feature = "async-tokio")]
+    Task(tokio::task::JoinHandle<()>),
+}
+
+impl FlushWorker {
+    /// Start a new worker on a separate thread. Without an `interval`, the worker only flushes the
+    /// buffer if it is asked to.
+    pub(crate) fn spawn_thread(
+        shipper: Arc<LogShipper>,
+        interval: Option<Duration>,
+    ) -> FlushWorker {
+        let signal = shipper.worker_signal.clone();
+        let handle = std::thread::Builder::new()
+            .name("fenrir-flush".to_string())
+            .spawn(move || loop {
+                // waiting for `Duration::MAX` means waiting until the worker is woken up
+                let due_in = match interval {
+                    Some(interval) => shipper.flush_due_in(interval),
+                    None => Duration::MAX,
+                };
+                if due_in.is_zero() {
+                    flush_on_worker(&shipper);
+                    continue;
+                }
+
+                let mut state = shipper.worker_signal.state.lock();
+                if !state.stopped && !state.flush_requested {
+                    shipper.worker_signal.condvar.wait_for(&mut state, due_in);
+                }
+                if state.stopped {
+                    break;
+                }
+                if std::mem::take(&mut state.flush_requested) {
+                    drop(state);
+                    flush_on_worker(&shipper);
+                }
+            })
+            .expect("Could not spawn the thread for flushing the logs");
+
+        FlushWorker::Thread {
+            signal,
+            handle: Some(handle),
+        }
+    }
+
+    /// Start a new worker on a separate thread which replays the batches stored in the spool of
+    /// the `shipper` periodically (starting immediately, so batches spooled before the application
+    /// was restarted are sent as soon as possible).
+    pub(crate) fn spawn_replay_thread(shipper: Arc<LogShipper>) -> FlushWorker {
+        let signal = Arc::new(WorkerSignal::default());
+        let worker_signal = signal.clone();
+        let handle = std::thread::Builder::new()
+            .name("fenrir-replay".to_string())
+            .spawn(move || loop {
+                if let Some(spool) = &shipper.spool {
+                    spool.replay(shipper.backend.as_ref(), || {
+                        worker_signal.state.lock().stopped
+                    });
+                }
+
+                let mut state = worker_signal.state.lock();
+                if !state.stopped {
+                    worker_signal.condvar.wait_for(&mut state, REPLAY_INTERVAL);
+                }
+                if state.stopped {
+                    break;
+                }
+            })
+            .expect("Could not spawn the thread for replaying the spooled logs");
+
+        FlushWorker::Thread {
+            signal,
+            handle: Some(handle),
+        }
+    }
+
+    /// Start a new worker as a task on the supplied tokio runtime.
+    #[cfg(feature = "async-tokio")]
+    pub(crate) fn spawn_task(
+        shipper: Arc<LogShipper>,
+        interval: Duration,
+        runtime_handle: &tokio::runtime::Handle,
+    ) -> FlushWorker {
+        FlushWorker::Task(runtime_handle.spawn(async move {
+            loop {
+                let due_in = shipper.flush_due_in(interval);
+                if due_in.is_zero() {
+                    flush_on_worker(&shipper);
+                    continue;
+                }
+                tokio::time::sleep(due_in).await;
+            }
+        }))
+    }
+}
+
+impl Drop for FlushWorker {
+    fn drop(&mut self) {
+        match self {
+            FlushWorker::Thread { signal, handle } => {
+                signal.stop();
+                if let Some(handle) = handle.take() {
+                    let _ = handle.join();
+                }
+            }
+            #[cfg(feature = "async-tokio")]
+            FlushWorker::Task(handle) => handle.abort(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{wait_until, CountingBackend};
+    use crate::worker::{FlushWorker, WorkerSignal};
+    use crate::{
+        noop_serializer, BufferLimits, Compression, Encoder, LogBuffer, LogEntry, LogShipper,
+        SerializationFormat,
+    };
+    use parking_lot::{Condvar, Mutex};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn counting_shipper() -> (Arc<LogShipper>, Arc<AtomicUsize>) {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let shipper = Arc::new(LogShipper {
+            backend: Box::new(CountingBackend(counter.clone())),
+            encoder: Encoder {
+                serializer: noop_serializer,
+                format: SerializationFormat::None,
+                compression: Compression::None,
+            },
+            log_stream: Mutex::new(LogBuffer::default()),
+            limits: BufferLimits::default(),
+            max_batch_bytes: None,
+            spool: None,
+            space_available: Condvar::new(),
+            undelivered: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            background_dispatch: false,
+            worker_signal: Arc::new(WorkerSignal::default()),
+            error_handler: Default::default(),
+        });
+        (shipper, counter)
+    }
+
+    #[test]
+    fn the_worker_thread_flushes_entries_older_than_the_interval() {
+        let (shipper, counter) = counting_shipper();
+        let _worker = FlushWorker::spawn_thread(shipper.clone(), Some(Duration::from_millis(50)));
+
+        let entry = LogEntry {
+            timestamp: Duration::ZERO,
+            line: "message".to_string(),
+            metadata: BTreeMap::new(),
+        };
+        shipper.log_stream.lock().push(None, BTreeMap::new(), entry);
+
+        assert!(wait_until(|| counter.load(Ordering::SeqCst) == 1));
+        assert!(shipper.log_stream.lock().take().0.is_empty());
+    }
+
+    #[test]
+    fn the_worker_thread_does_not_flush_an_empty_buffer() {
+        let (shipper, counter) = counting_shipper();
+        let worker = FlushWorker::spawn_thread(shipper, Some(Duration::from_millis(10)));
+        // a slow worker can only make this test pass, but never fail
+        std::thread::sleep(Duration::from_millis(100));
+        drop(worker);
+
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn the_worker_thread_flushes_the_buffer_if_requested() {
+        let (shipper, counter) = counting_shipper();
+        let worker = FlushWorker::spawn_thread(shipper.clone(), None);
+
+        let entry = LogEntry {
+            timestamp: Duration::ZERO,
+            line: "message".to_string(),
+            metadata: BTreeMap::new(),
+        };
+        shipper.log_stream.lock().push(None, BTreeMap::new(), entry);
+        assert!(shipper.worker_signal.request_flush());
+        assert!(wait_until(|| counter.load(Ordering::SeqCst) == 1));
+
+        drop(worker);
+        assert!(!shipper.worker_signal.request_flush());
+    }
+}